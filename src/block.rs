@@ -1,3 +1,5 @@
 pub mod bitmap;
 pub mod inode;
+pub mod log;
+pub mod record;
 pub mod sblock;
@@ -1,7 +1,12 @@
+use crate::block::inode::extract_block_pointer;
+use crate::block::record;
 use crate::block::sblock::superblock;
+use crate::BLOCK_SIZE;
+
+const BITS_PER_BLOCK: usize = BLOCK_SIZE * 8;
 
 pub fn check(img: &[u8], block_num: usize, sblock: &superblock) -> bool {
-    let bmapstart = sblock.bmapstart as usize;
+    let bmapstart = sblock.bmapstart as usize * BLOCK_SIZE; // byte offset of free bit map
     let byte: u8 = img[bmapstart + block_num / 8];
     match block_num % 8 {
         0 => byte & 0b00000001 == 0b00000001,
@@ -17,7 +22,8 @@ pub fn check(img: &[u8], block_num: usize, sblock: &superblock) -> bool {
 }
 
 pub fn switch(img: &mut [u8], block_num: usize, sblock: &superblock) {
-    let bmapstart = sblock.bmapstart as usize;
+    let bmapstart = sblock.bmapstart as usize * BLOCK_SIZE; // byte offset of free bit map
+    record::access(img, (bmapstart + block_num / 8) / BLOCK_SIZE);
     let byte = &mut img[bmapstart + block_num / 8];
     match block_num % 8 {
         0 => *byte ^= 0b00000001,
@@ -31,3 +37,16 @@ pub fn switch(img: &mut [u8], block_num: usize, sblock: &superblock) {
         _ => panic!("bitmap::switch: exhausting pattern must not happen"),
     }
 }
+
+// mark the block as used (used == true) or free in free bit map
+pub fn mark(img: &[u8], block_num: usize, used: bool, sblock: &superblock) {
+    let bmap_block =
+        extract_block_pointer(img, sblock.bmapstart as usize + block_num / BITS_PER_BLOCK);
+    let byte = &mut bmap_block[block_num % BITS_PER_BLOCK / 8];
+    let bit = 1u8 << (block_num % 8);
+    if used {
+        *byte |= bit;
+    } else {
+        *byte &= !bit;
+    }
+}
@@ -1,3 +1,6 @@
+use crate::block::bitmap;
+use crate::block::record;
+use crate::block::sblock;
 use crate::block::sblock::superblock;
 use crate::BLOCK_SIZE;
 use memmap::MmapMut;
@@ -14,7 +17,7 @@ pub const ROOT_INODE: usize = 1; // inode number of root directory("/")
 
 // dinode.type
 #[repr(i16)]
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 #[allow(non_camel_case_types)]
 pub enum InodeType {
     T_DIR = 1,
@@ -60,9 +63,12 @@ pub fn explore_path<'a>(
     sblock: &superblock,
 ) -> Result<(&'a dinode, usize), String> {
     let mut current_inode: &dinode = extract_inode_pointer_im(&img, ROOT_INODE, &sblock);
-    let mut current_inode_num: usize = 0;
+    let mut current_inode_num: usize = ROOT_INODE;
     if path != "/" {
-        'directory: for file_name in path.split("/").skip(1) {
+        'directory: for file_name in path.split('/').filter(|s| !s.is_empty()) {
+            if current_inode.r#type != InodeType::T_DIR {
+                return Err(format!("{}: not a directory", path));
+            }
             for i in 0..NDIRECT {
                 if current_inode.addrs[i] == 0 {
                     break;
@@ -124,6 +130,10 @@ pub fn extract_inode_pointer<'a>(
         std::process::exit(1);
     }
     let inodestart_byte = sblock.inodestart as usize * BLOCK_SIZE;
+    record::access(
+        img,
+        (inodestart_byte + inode_num * DINODE_SIZE) / BLOCK_SIZE,
+    );
     unsafe {
         u8_slice_as_dinode(
             &img[inodestart_byte + inode_num * DINODE_SIZE
@@ -156,6 +166,13 @@ pub fn extract_inode_pointer_im<'a>(
         )
     }
 }
+
+// raw value of dinode.type (it may not be a valid InodeType on broken image)
+pub fn raw_inode_type(img: &[u8], inode_num: usize, sblock: &superblock) -> i16 {
+    let addr = sblock.inodestart as usize * BLOCK_SIZE + inode_num * DINODE_SIZE;
+    i16::from_le_bytes([img[addr], img[addr + 1]])
+}
+
 pub fn dinode_as_u8_slice(d: &dinode) -> &[u8] {
     unsafe { std::slice::from_raw_parts((d as *const dinode) as *const u8, DINODE_SIZE) }
 }
@@ -174,6 +191,7 @@ pub fn extract_dirent_pointer<'a>(
     offset: usize,
 ) -> &'a mut dirent {
     let addr = block_num * BLOCK_SIZE + offset * DIRENT_SIZE;
+    record::access(img, block_num);
     unsafe { u8_slice_as_dirent(&img[addr..addr + DIRENT_SIZE]) }
 }
 
@@ -196,6 +214,7 @@ pub fn extract_dirents_pointer<'a>(
     img: &'a [u8],
     block_num: usize,
 ) -> &'a mut [dirent; BLOCK_SIZE / DIRENT_SIZE] {
+    record::access(img, block_num);
     unsafe { u8_slice_as_dirents(&img[block_num * BLOCK_SIZE..(block_num + 1) * BLOCK_SIZE]) }
 }
 
@@ -204,6 +223,7 @@ pub unsafe fn u8_slice_as_block<'a>(p: &'a [u8]) -> &'a mut [u8; BLOCK_SIZE] {
 }
 
 pub fn extract_block_pointer<'a>(img: &'a [u8], block_num: usize) -> &'a mut [u8; BLOCK_SIZE] {
+    record::access(img, block_num);
     unsafe { u8_slice_as_block(&img[block_num * BLOCK_SIZE..(block_num + 1) * BLOCK_SIZE]) }
 }
 
@@ -228,13 +248,14 @@ pub fn extract_indirect_reference_block_pointer<'a>(
     img: &'a [u8],
     block_num: usize,
 ) -> &'a mut [u32; U32_PER_BLOCK] {
+    record::access(img, block_num);
     unsafe { u8_slice_as_u32_slice(&img[block_num * BLOCK_SIZE..(block_num + 1) * BLOCK_SIZE]) }
 }
 
 pub fn search_for_available_inode(
     img: &memmap::MmapMut,
     sblock: &superblock,
-) -> Result<usize, &'static str> {
+) -> Result<usize, String> {
     let inodestart_addr = sblock.inodestart as usize * BLOCK_SIZE;
     for i in 1..sblock.ninodes as usize {
         if img[inodestart_addr + i * DINODE_SIZE as usize
@@ -245,21 +266,402 @@ pub fn search_for_available_inode(
             return Ok(i);
         }
     }
-    Err("cannot allocate inode.")
+    Err("cannot allocate inode.".to_string())
 }
 
+// allocate a zeroed data block from free bit map, like balloc() of xv6
 pub fn search_for_available_dblock(
     img: &memmap::MmapMut,
     sblock: &superblock,
-) -> Result<usize, &'static str> {
-    let datastart = (sblock.bmapstart + sblock.size / 8) as usize; // start block number of data block
-    for i in datastart..sblock.size as usize {
-        if img[i * BLOCK_SIZE..(i + 1) * BLOCK_SIZE]
+) -> Result<usize, String> {
+    for i in sblock::datastart(sblock)..sblock.size as usize {
+        if !bitmap::check(img, i, sblock) {
+            bitmap::mark(img, i, true, sblock);
+            *extract_block_pointer(img, i) = [0u8; BLOCK_SIZE];
+            return Ok(i);
+        }
+    }
+    Err("cannot allocate data block.".to_string())
+}
+
+// mark the block free, like bfree() of xv6 (it is zeroed when allocated again)
+pub fn free_block(img: &[u8], block_num: usize, sblock: &superblock) {
+    bitmap::mark(img, block_num, false, sblock);
+}
+
+// name of directory entry without trailing NULs
+pub fn dirent_name(entry: &dirent) -> &str {
+    from_utf8(&entry.name).unwrap().trim_matches(char::from(0))
+}
+
+// split the given path into its parent directory and its last component
+pub fn split_path(path: &str) -> (&str, &str) {
+    let path = path.trim_end_matches('/');
+    match path.rfind('/') {
+        Some(0) | None => ("/", path.trim_start_matches('/')),
+        Some(i) => (&path[..i], &path[i + 1..]),
+    }
+}
+
+pub fn make_dirent(inode_num: usize, name: &str) -> Result<dirent, String> {
+    if name.is_empty() {
+        return Err("file name must not be empty".to_string());
+    }
+    if name.len() > DIRSIZ {
+        return Err(format!(
+            "{}: file name too long (must be {} bytes or shorter)",
+            name, DIRSIZ
+        ));
+    }
+    let mut new_name: [u8; DIRSIZ] = [0u8; DIRSIZ];
+    new_name[..name.len()].copy_from_slice(name.as_bytes());
+    Ok(dirent {
+        inum: inode_num as u16,
+        name: new_name,
+    })
+}
+
+// link new_dirent into the directory like dirlink() of xv6:
+// reuse the first empty slot within size, or append one at the end of the directory.
+pub fn add_dirent(
+    img: &MmapMut,
+    dir_inode_num: usize,
+    new_dirent: dirent,
+    sblock: &superblock,
+) -> Result<(), String> {
+    let dir_inode: &dinode = extract_inode_pointer_im(img, dir_inode_num, sblock);
+    let new_name = dirent_name(&new_dirent);
+    if lookup_dirent(img, dir_inode, new_name).is_some() {
+        // file already exists(for now, overriding is not compatible)
+        return Err(format!("{}: already exists.", new_name));
+    }
+
+    let size = dir_inode.size as usize;
+    for off in (0..size).step_by(DIRENT_SIZE) {
+        let block_num = inode_block_number(img, dir_inode, off / BLOCK_SIZE);
+        if block_num == 0 {
+            continue;
+        }
+        let entry: &mut dirent =
+            extract_dirent_pointer(img, block_num, off % BLOCK_SIZE / DIRENT_SIZE);
+        if entry.inum == 0 {
+            *entry = new_dirent;
+            return Ok(());
+        }
+    }
+
+    // no empty slot, so append at the end
+    if size / BLOCK_SIZE >= NDIRECT + U32_PER_BLOCK {
+        return Err(format!("cannot add {}: directory is full", new_name));
+    }
+    let block_num = bmap(img, dir_inode_num, size / BLOCK_SIZE, sblock)?;
+    *extract_dirent_pointer(img, block_num, size % BLOCK_SIZE / DIRENT_SIZE) = new_dirent;
+    extract_inode_pointer(img, dir_inode_num, sblock).size += DIRENT_SIZE as u32;
+    Ok(())
+}
+
+// clear the entry at (block number, offset), and shrink the directory
+// so that it ends with its last used entry
+pub fn remove_dirent(
+    img: &MmapMut,
+    dir_inode_num: usize,
+    block_num: usize,
+    offset: usize,
+    sblock: &superblock,
+) {
+    *extract_dirent_pointer(img, block_num, offset) = dirent {
+        inum: 0,
+        name: [0u8; DIRSIZ],
+    };
+
+    let dir_inode: &mut dinode = extract_inode_pointer(img, dir_inode_num, sblock);
+    let mut size = dir_inode.size as usize;
+    while size >= DIRENT_SIZE {
+        let off = size - DIRENT_SIZE;
+        let block_num = inode_block_number(img, dir_inode, off / BLOCK_SIZE);
+        if block_num != 0
+            && extract_dirent_pointer(img, block_num, off % BLOCK_SIZE / DIRENT_SIZE).inum != 0
+        {
+            break;
+        }
+        size = off;
+    }
+    dir_inode.size = size as u32;
+    truncate_blocks(img, dir_inode_num, size.div_ceil(BLOCK_SIZE), sblock);
+}
+
+// pack live entries of the directory to its head ("." and ".." stay first),
+// optionally sorting the others by name, and release blocks no longer needed
+pub fn compact_directory(
+    img: &MmapMut,
+    dir_inode_num: usize,
+    sorted: bool,
+    sblock: &superblock,
+) -> Result<(), String> {
+    let dir_inode: &mut dinode = extract_inode_pointer(img, dir_inode_num, sblock);
+    let mut entries: Vec<dirent> = Vec::new();
+    for off in (0..dir_inode.size as usize).step_by(DIRENT_SIZE) {
+        let block_num = inode_block_number(img, dir_inode, off / BLOCK_SIZE);
+        if block_num == 0 {
+            continue;
+        }
+        let entry = extract_dirent_pointer(img, block_num, off % BLOCK_SIZE / DIRENT_SIZE);
+        if entry.inum != 0 {
+            entries.push(*entry);
+        }
+    }
+    if sorted {
+        entries.sort_by(|a, b| {
+            let rank = |name| match name {
+                "." => 0,
+                ".." => 1,
+                _ => 2,
+            };
+            let (a, b) = (dirent_name(a), dirent_name(b));
+            (rank(a), a).cmp(&(rank(b), b))
+        });
+    }
+
+    let size = entries.len() * DIRENT_SIZE;
+    for off in (0..size.div_ceil(BLOCK_SIZE) * BLOCK_SIZE).step_by(DIRENT_SIZE) {
+        // holes in the directory are filled, not to lose entries
+        let block_num = bmap(img, dir_inode_num, off / BLOCK_SIZE, sblock)?;
+        *extract_dirent_pointer(img, block_num, off % BLOCK_SIZE / DIRENT_SIZE) =
+            match entries.get(off / DIRENT_SIZE) {
+                Some(entry) => *entry,
+                None => dirent {
+                    inum: 0,
+                    name: [0u8; DIRSIZ],
+                },
+            };
+    }
+    dir_inode.size = size as u32;
+    truncate_blocks(img, dir_inode_num, size.div_ceil(BLOCK_SIZE), sblock);
+    Ok(())
+}
+
+// change size of the file: blocks beyond new size are released, and when it grows,
+// zeroed blocks are allocated up to new size (or left as holes if sparse)
+pub fn resize_inode(
+    img: &MmapMut,
+    inode_num: usize,
+    size: usize,
+    sparse: bool,
+    sblock: &superblock,
+) -> Result<(), String> {
+    if size > BLOCK_SIZE * (NDIRECT + U32_PER_BLOCK) {
+        return Err(format!(
+            "file is too large (must be {} bytes or smaller)",
+            BLOCK_SIZE * (NDIRECT + U32_PER_BLOCK)
+        ));
+    }
+    let inode: &mut dinode = extract_inode_pointer(img, inode_num, sblock);
+    let old_size = inode.size as usize;
+
+    // bytes beyond end of file in its last block are kept zero
+    let end = size.min(old_size);
+    let block_num = inode_block_number(img, inode, end / BLOCK_SIZE);
+    if end % BLOCK_SIZE != 0 && block_num != 0 {
+        extract_block_pointer(img, block_num)[end % BLOCK_SIZE..].fill(0);
+    }
+
+    truncate_blocks(img, inode_num, size.div_ceil(BLOCK_SIZE), sblock);
+    if !sparse {
+        for fbn in old_size.div_ceil(BLOCK_SIZE)..size.div_ceil(BLOCK_SIZE) {
+            if let Err(e) = bmap(img, inode_num, fbn, sblock) {
+                // release what has been allocated above
+                truncate_blocks(img, inode_num, old_size.div_ceil(BLOCK_SIZE), sblock);
+                return Err(e);
+            }
+        }
+    }
+    inode.size = size as u32;
+    Ok(())
+}
+
+// write data into the file at offset like writei() of xv6, allocating blocks as needed
+pub fn write_data(
+    img: &MmapMut,
+    inode_num: usize,
+    offset: usize,
+    data: &[u8],
+    sblock: &superblock,
+) -> Result<(), String> {
+    let inode: &mut dinode = extract_inode_pointer(img, inode_num, sblock);
+    let old_size = inode.size as usize;
+    let end = offset + data.len();
+    if offset > old_size {
+        return Err(format!(
+            "offset {} is beyond end of file ({} bytes)",
+            offset, old_size
+        ));
+    }
+    if end > BLOCK_SIZE * (NDIRECT + U32_PER_BLOCK) {
+        return Err(format!(
+            "file is too large (must be {} bytes or smaller)",
+            BLOCK_SIZE * (NDIRECT + U32_PER_BLOCK)
+        ));
+    }
+
+    // map every block first, so that nothing is written when allocation fails
+    let mut block_nums: Vec<usize> = Vec::new();
+    for fbn in offset / BLOCK_SIZE..end.div_ceil(BLOCK_SIZE) {
+        match bmap(img, inode_num, fbn, sblock) {
+            Ok(block_num) => block_nums.push(block_num),
+            Err(e) => {
+                // release what has been allocated above
+                truncate_blocks(img, inode_num, old_size.div_ceil(BLOCK_SIZE), sblock);
+                return Err(e);
+            }
+        }
+    }
+    for (fbn, block_num) in (offset / BLOCK_SIZE..).zip(block_nums) {
+        let start = offset.max(fbn * BLOCK_SIZE);
+        let stop = end.min((fbn + 1) * BLOCK_SIZE);
+        extract_block_pointer(img, block_num)[start - fbn * BLOCK_SIZE..stop - fbn * BLOCK_SIZE]
+            .copy_from_slice(&data[start - offset..stop - offset]);
+    }
+    if end > old_size {
+        inode.size = end as u32;
+    }
+    Ok(())
+}
+
+// block number of the fbn-th block of the inode (0 if not allocated)
+pub fn inode_block_number(img: &[u8], inode: &dinode, fbn: usize) -> usize {
+    if fbn < NDIRECT {
+        return inode.addrs[fbn] as usize;
+    }
+    if fbn >= NDIRECT + U32_PER_BLOCK || inode.addrs[NDIRECT] == 0 {
+        return 0;
+    }
+    extract_indirect_reference_block_pointer_im(img, inode.addrs[NDIRECT] as usize)[fbn - NDIRECT]
+        as usize
+}
+
+// block number of the fbn-th block of the inode, allocating it if needed, like bmap() of xv6
+pub fn bmap(
+    img: &MmapMut,
+    inode_num: usize,
+    fbn: usize,
+    sblock: &superblock,
+) -> Result<usize, String> {
+    let inode: &mut dinode = extract_inode_pointer(img, inode_num, sblock);
+    if fbn < NDIRECT {
+        if inode.addrs[fbn] == 0 {
+            inode.addrs[fbn] = search_for_available_dblock(img, sblock)? as u32;
+        }
+        return Ok(inode.addrs[fbn] as usize);
+    }
+    if fbn >= NDIRECT + U32_PER_BLOCK {
+        return Err(format!(
+            "file is too large (must be {} bytes or smaller)",
+            BLOCK_SIZE * (NDIRECT + U32_PER_BLOCK)
+        ));
+    }
+    if inode.addrs[NDIRECT] == 0 {
+        inode.addrs[NDIRECT] = search_for_available_dblock(img, sblock)? as u32;
+    }
+    let ref_block = extract_indirect_reference_block_pointer(img, inode.addrs[NDIRECT] as usize);
+    if ref_block[fbn - NDIRECT] == 0 {
+        ref_block[fbn - NDIRECT] = search_for_available_dblock(img, sblock)? as u32;
+    }
+    Ok(ref_block[fbn - NDIRECT] as usize)
+}
+
+// release blocks of the inode from the nblocks-th one, and also the indirect
+// reference block if it is no longer needed (size is not changed)
+pub fn truncate_blocks(img: &MmapMut, inode_num: usize, nblocks: usize, sblock: &superblock) {
+    let inode: &mut dinode = extract_inode_pointer(img, inode_num, sblock);
+    for fbn in nblocks..NDIRECT {
+        if inode.addrs[fbn] != 0 {
+            free_block(img, inode.addrs[fbn] as usize, sblock);
+            inode.addrs[fbn] = 0;
+        }
+    }
+    if inode.addrs[NDIRECT] == 0 {
+        return;
+    }
+    let ref_block = extract_indirect_reference_block_pointer(img, inode.addrs[NDIRECT] as usize);
+    for fbn in nblocks.max(NDIRECT)..NDIRECT + U32_PER_BLOCK {
+        if ref_block[fbn - NDIRECT] != 0 {
+            free_block(img, ref_block[fbn - NDIRECT] as usize, sblock);
+            ref_block[fbn - NDIRECT] = 0;
+        }
+    }
+    if nblocks <= NDIRECT {
+        free_block(img, inode.addrs[NDIRECT] as usize, sblock);
+        inode.addrs[NDIRECT] = 0;
+    }
+}
+
+// data block numbers referred by the inode, in order (indirect reference block is not included)
+pub fn inode_block_numbers(img: &[u8], inode: &dinode) -> Vec<usize> {
+    let mut block_nums: Vec<usize> = inode.addrs[..NDIRECT]
+        .iter()
+        .filter(|&&b| b != 0)
+        .map(|&b| b as usize)
+        .collect();
+    if inode.addrs[NDIRECT] != 0 {
+        block_nums.extend(
+            extract_indirect_reference_block_pointer_im(img, inode.addrs[NDIRECT] as usize)
+                .iter()
+                .filter(|&&b| b != 0)
+                .map(|&b| b as usize),
+        );
+    }
+    block_nums
+}
+
+// read contents of the file, walking direct and indirect reference blocks
+// (holes, that is blocks not allocated, are read as zeros)
+pub fn read_data(img: &[u8], inode: &dinode) -> Vec<u8> {
+    let file_size: usize = inode.size as usize;
+    let mut data: Vec<u8> = Vec::with_capacity(file_size);
+    for fbn in 0..file_size.div_ceil(BLOCK_SIZE) {
+        let len = BLOCK_SIZE.min(file_size - data.len());
+        match inode_block_number(img, inode, fbn) {
+            0 => data.resize(data.len() + len, 0),
+            block_num => {
+                data.extend_from_slice(&img[block_num * BLOCK_SIZE..block_num * BLOCK_SIZE + len])
+            }
+        }
+    }
+    data
+}
+
+// search the directory for the entry, and return (inode number, block number, offset) of it
+pub fn lookup_dirent(img: &[u8], dir_inode: &dinode, name: &str) -> Option<(usize, usize, usize)> {
+    for block_num in inode_block_numbers(img, dir_inode) {
+        for (j, entry) in extract_dirents_pointer_im(img, block_num)
             .iter()
-            .all(|&x| x == 0)
+            .enumerate()
         {
-            return Ok(i);
+            if entry.inum != 0 && dirent_name(entry) == name {
+                return Some((entry.inum as usize, block_num, j));
+            }
         }
     }
-    Err("cannot allocate data block.")
+    None
+}
+
+// release data blocks (including indirect reference block) of the inode
+pub fn free_data_blocks(img: &[u8], inode: &dinode, sblock: &superblock) {
+    for block_num in inode_block_numbers(img, inode) {
+        free_block(img, block_num, sblock);
+    }
+    if inode.addrs[NDIRECT] != 0 {
+        free_block(img, inode.addrs[NDIRECT] as usize, sblock);
+    }
+}
+
+pub fn clear_inode(img: &[u8], inode_num: usize, sblock: &superblock) {
+    *extract_inode_pointer(img, inode_num, sblock) = dinode {
+        r#type: InodeType::ZERO,
+        major: 0,
+        minor: 0,
+        nlink: 0,
+        size: 0,
+        addrs: [0; NDIRECT + 1],
+    };
 }
@@ -0,0 +1,175 @@
+use crate::block::inode::{
+    extract_block_pointer, extract_indirect_reference_block_pointer,
+    extract_indirect_reference_block_pointer_im, extract_inode_pointer_im, inode_block_numbers,
+    raw_inode_type, InodeType, NDIRECT, ROOT_INODE, U32_PER_BLOCK,
+};
+use crate::block::sblock::{datastart, superblock};
+use crate::BLOCK_SIZE;
+use memmap::MmapMut;
+
+// log region (same as xv6 log.c):
+// [ header block | logged block 1 | logged block 2 | ... ]
+// header block holds count of committed blocks (n) and their home block numbers.
+// n != 0 means that the transaction is committed but not installed yet.
+
+// home block numbers of committed blocks recorded in log header (empty if nothing is committed)
+pub fn read_header(img: &[u8], sblock: &superblock) -> Result<Vec<u32>, String> {
+    if sblock.nlog == 0 {
+        return Ok(Vec::new());
+    }
+    let header = extract_indirect_reference_block_pointer_im(img, sblock.logstart as usize);
+    let n = header[0] as i32;
+    if n < 0 || n as usize >= sblock.nlog as usize || n as usize >= U32_PER_BLOCK {
+        return Err(format!(
+            "log header is broken: count {} is out of range (log has {} blocks)",
+            n,
+            sblock.nlog as usize - 1
+        ));
+    }
+    Ok(header[1..=n as usize].to_vec())
+}
+
+pub fn write_header(img: &[u8], block_nums: &[u32], sblock: &superblock) {
+    let header = extract_indirect_reference_block_pointer(img, sblock.logstart as usize);
+    header[0] = block_nums.len() as u32;
+    header[1..=block_nums.len()].copy_from_slice(block_nums);
+}
+
+// copy committed blocks to their home locations and clear the header,
+// like recover_from_log() of xv6. return the home block numbers installed.
+pub fn replay(img: &[u8], sblock: &superblock) -> Result<Vec<u32>, String> {
+    let block_nums = read_header(img, sblock)?;
+    if let Some(b) = block_nums
+        .iter()
+        .find(|&&b| b as usize >= sblock.size as usize)
+    {
+        return Err(format!(
+            "log header is broken: block {} is out of image (size is {})",
+            b, sblock.size
+        ));
+    }
+    for (i, &block_num) in block_nums.iter().enumerate() {
+        let logged = *extract_block_pointer(img, sblock.logstart as usize + 1 + i);
+        *extract_block_pointer(img, block_num as usize) = logged;
+    }
+    write_header(img, &[], sblock);
+    Ok(block_nums)
+}
+
+// header block with block_nums, keeping the rest of base (current header block) as is
+fn header_block(base: &[u8], block_nums: &[u32]) -> Vec<u8> {
+    let mut header = base.to_vec();
+    header[..4].copy_from_slice(&(block_nums.len() as u32).to_le_bytes());
+    for (i, block_num) in block_nums.iter().enumerate() {
+        header[4 * (i + 1)..4 * (i + 2)].copy_from_slice(&block_num.to_le_bytes());
+    }
+    header
+}
+
+// blocks telling the structure of file system in img: blocks before data region,
+// and directory blocks and indirect reference blocks of allocated inodes
+fn structural_blocks(img: &[u8], sblock: &superblock) -> Vec<bool> {
+    let size = sblock.size as usize;
+    let mut structural: Vec<bool> = (0..size).map(|b| b < datastart(sblock)).collect();
+    let mut mark = |b: usize| {
+        if b < size {
+            structural[b] = true;
+        }
+    };
+    for inode_num in ROOT_INODE..sblock.ninodes as usize {
+        let r#type = raw_inode_type(img, inode_num, sblock);
+        if r#type < InodeType::T_DIR as i16 || r#type > InodeType::T_DEV as i16 {
+            continue;
+        }
+        let inode = extract_inode_pointer_im(img, inode_num, sblock);
+        let indirect = inode.addrs[NDIRECT] as usize;
+        if indirect >= size {
+            continue;
+        }
+        mark(indirect);
+        if r#type == InodeType::T_DIR as i16 {
+            inode_block_numbers(img, inode)
+                .into_iter()
+                .for_each(&mut mark);
+        }
+    }
+    structural
+}
+
+// block writes (block number, contents) updating old image into new one through log,
+// in the order of commit() of xv6: copies into log, header (commit point),
+// blocks at their home locations, and cleared header.
+// changes larger than log are split into several transactions, like filewrite() of xv6:
+// blocks which are file data or free in old image go first, nlog - 1 blocks at a time,
+// and the rest (metadata, directories and indirect reference blocks) is committed last,
+// so the image stays consistent after every transaction.
+pub fn transaction_writes(
+    old: &[u8],
+    new: &[u8],
+    sblock: &superblock,
+) -> Result<Vec<(usize, Vec<u8>)>, String> {
+    let block = |img: &[u8], b: usize| img[b * BLOCK_SIZE..(b + 1) * BLOCK_SIZE].to_vec();
+    let changed: Vec<usize> = (0..sblock.size as usize)
+        .filter(|&b| block(old, b) != block(new, b))
+        .collect();
+    if changed.is_empty() {
+        return Ok(Vec::new());
+    }
+    let logstart = sblock.logstart as usize;
+    if let Some(b) = changed
+        .iter()
+        .find(|&&b| b >= logstart && b < logstart + sblock.nlog as usize)
+    {
+        return Err(format!("block {} in log region cannot be logged", b));
+    }
+    if !read_header(old, sblock)?.is_empty() {
+        return Err(
+            "log has committed blocks which are not installed (run `log replay` first)".to_string(),
+        );
+    }
+    // header block holds count and block numbers, like read_header() expects
+    let capacity = (sblock.nlog as usize)
+        .saturating_sub(1)
+        .min(U32_PER_BLOCK - 1);
+    let structural = structural_blocks(old, sblock);
+    let (last, data): (Vec<usize>, Vec<usize>) = changed.into_iter().partition(|&b| structural[b]);
+    if capacity == 0 || last.len() > capacity {
+        return Err(format!(
+            "transaction is too large: {} blocks of metadata, directories and indirect reference blocks are changed, but log holds only {} blocks",
+            last.len(),
+            capacity
+        ));
+    }
+
+    let mut writes: Vec<(usize, Vec<u8>)> = Vec::new();
+    let mut header = block(old, logstart);
+    for blocks in data.chunks(capacity).chain(Some(&last[..])) {
+        if blocks.is_empty() {
+            continue;
+        }
+        let block_nums: Vec<u32> = blocks.iter().map(|&b| b as u32).collect();
+        for (i, &b) in blocks.iter().enumerate() {
+            writes.push((logstart + 1 + i, block(new, b)));
+        }
+        header = header_block(&header, &block_nums);
+        writes.push((logstart, header.clone()));
+        for &b in blocks.iter() {
+            writes.push((b, block(new, b)));
+        }
+        header = header_block(&header, &[]);
+        writes.push((logstart, header.clone()));
+    }
+    Ok(writes)
+}
+
+// apply changes from img to new through log, flushing each block write
+// so that they reach the disk in order
+pub fn commit(img: &mut MmapMut, new: &[u8], sblock: &superblock) -> Result<(), String> {
+    let writes = transaction_writes(img, new, sblock)?;
+    for (block_num, data) in writes.iter() {
+        img[block_num * BLOCK_SIZE..(block_num + 1) * BLOCK_SIZE].copy_from_slice(data);
+        img.flush_range(block_num * BLOCK_SIZE, BLOCK_SIZE)
+            .map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
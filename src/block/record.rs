@@ -0,0 +1,71 @@
+use crate::BLOCK_SIZE;
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+
+// recorder of block writes, used by crashtest to crash the image in the order the command writes.
+// accessors (extract_*_pointer and bitmap::switch) report each block before handing it out
+// for writing. as the write itself happens later through the reference, every time a block is
+// reported, blocks reported so far are compared with their last recorded contents, and changed
+// ones are recorded as written.
+struct Recorder {
+    base: usize,               // address of the recorded image
+    shadow: Vec<u8>,           // the image as of the last recorded writes
+    accessed: BTreeSet<usize>, // blocks reported so far
+    writes: Vec<(usize, Vec<u8>)>,
+}
+
+thread_local! {
+    static RECORDER: RefCell<Option<Recorder>> = const { RefCell::new(None) };
+}
+
+impl Recorder {
+    fn record_changed<I: Iterator<Item = usize>>(&mut self, img: &[u8], block_nums: I) {
+        for block_num in block_nums {
+            let range = block_num * BLOCK_SIZE..(block_num + 1) * BLOCK_SIZE;
+            if img[range.clone()] != self.shadow[range.clone()] {
+                self.shadow[range.clone()].copy_from_slice(&img[range.clone()]);
+                self.writes.push((block_num, img[range].to_vec()));
+            }
+        }
+    }
+}
+
+// start recording writes into img
+pub fn start(img: &[u8]) {
+    RECORDER.with(|r| {
+        *r.borrow_mut() = Some(Recorder {
+            base: img.as_ptr() as usize,
+            shadow: img.to_vec(),
+            accessed: BTreeSet::new(),
+            writes: Vec::new(),
+        })
+    });
+}
+
+// the block of img is about to be handed out for writing
+pub fn access(img: &[u8], block_num: usize) {
+    RECORDER.with(|r| {
+        if let Some(recorder) = r.borrow_mut().as_mut() {
+            if img.as_ptr() as usize != recorder.base || (block_num + 1) * BLOCK_SIZE > img.len() {
+                return;
+            }
+            let accessed: Vec<usize> = recorder.accessed.iter().copied().collect();
+            recorder.record_changed(img, accessed.into_iter());
+            recorder.accessed.insert(block_num);
+        }
+    });
+}
+
+// stop recording, and return block writes (block number, contents) in order.
+// changes made without accessors are recorded last, in order of block number.
+pub fn finish(img: &[u8]) -> Vec<(usize, Vec<u8>)> {
+    RECORDER.with(|r| match r.borrow_mut().take() {
+        Some(mut recorder) => {
+            let accessed: Vec<usize> = recorder.accessed.iter().copied().collect();
+            recorder.record_changed(img, accessed.into_iter());
+            recorder.record_changed(img, 0..img.len() / BLOCK_SIZE);
+            recorder.writes
+        }
+        None => Vec::new(),
+    })
+}
@@ -1,5 +1,8 @@
+use crate::block::inode::DINODE_SIZE;
 use crate::BLOCK_SIZE;
 
+pub const FSMAGIC: u32 = 0x10203040;
+
 // Super Block
 #[allow(non_camel_case_types)]
 #[derive(Debug, Copy, Clone)]
@@ -7,10 +10,10 @@ use crate::BLOCK_SIZE;
 pub struct superblock {
     pub magic: u32,      // Must be FSMAGIC
     pub size: u32,       // Size of file system image (blocks)
-    nblocks: u32,        // Number of data blocks
+    pub nblocks: u32,    // Number of data blocks
     pub ninodes: u32,    // Number of inodes.
-    nlog: u32,           // Number of log blocks
-    logstart: u32,       // Block number of first log block
+    pub nlog: u32,       // Number of log blocks
+    pub logstart: u32,   // Block number of first log block
     pub inodestart: u32, // Block number of first inode block
     pub bmapstart: u32,  // Block number of first free map block
 }
@@ -21,8 +24,56 @@ pub fn u8_slice_as_superblock(s: &[u8]) -> superblock {
     unsafe { std::mem::transmute(*p) }
 }
 
+pub fn superblock_as_u8_slice(s: &superblock) -> &[u8] {
+    unsafe {
+        std::slice::from_raw_parts(
+            (s as *const superblock) as *const u8,
+            std::mem::size_of::<superblock>(),
+        )
+    }
+}
+
+// disk layout (same as xv6 mkfs):
+// [ boot block | super block | log | inode blocks | free bit map | data blocks ]
+pub fn new_superblock(size: u32, ninodes: u32, nlog: u32) -> Result<superblock, String> {
+    let ninodeblocks = ninodes / (BLOCK_SIZE / DINODE_SIZE) as u32 + 1;
+    let nbitmap = size / (BLOCK_SIZE * 8) as u32 + 1;
+    let nmeta = 2u32
+        .checked_add(nlog)
+        .and_then(|n| n.checked_add(ninodeblocks))
+        .and_then(|n| n.checked_add(nbitmap))
+        .ok_or_else(|| {
+            format!(
+                "metadata is too large: {} log blocks and {} inode blocks do not fit in a file system",
+                nlog, ninodeblocks
+            )
+        })?;
+    if size <= nmeta {
+        return Err(format!(
+            "size {} is too small: {} blocks are needed for metadata and root directory",
+            size,
+            nmeta as u64 + 1
+        ));
+    }
+    Ok(superblock {
+        magic: FSMAGIC,
+        size,
+        nblocks: size - nmeta,
+        ninodes,
+        nlog,
+        logstart: 2,
+        inodestart: 2 + nlog,
+        bmapstart: 2 + nlog + ninodeblocks,
+    })
+}
+
+// block number of the first data block (data region is the last nblocks blocks)
+pub fn datastart(s: &superblock) -> usize {
+    s.size.saturating_sub(s.nblocks) as usize
+}
+
 pub fn check_magic_number(s: &superblock) {
-    if s.magic != 0x10203040 {
+    if s.magic != FSMAGIC {
         eprintln!("magic number is invalid: {:x}", s.magic);
         std::process::exit(1);
     }
@@ -0,0 +1,398 @@
+use crate::block::bitmap;
+use crate::block::inode::*;
+use crate::block::sblock;
+use crate::block::sblock::superblock;
+use crate::fsck::Problem;
+use crate::BLOCK_SIZE;
+
+// names of corruptions given to `corrupt` subcommand
+pub const KINDS: [&str; 6] = [
+    "free-inode",
+    "dup-block",
+    "nlink",
+    "bitmap",
+    "size",
+    "dotdot",
+];
+
+// what corrupt() did, and problems fsck is expected to find (on an image which was consistent)
+pub struct Record {
+    pub description: String,
+    pub expected: Vec<Problem>,
+}
+
+// xorshift64* generator, so that the same seed always gives the same corruption
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Rng {
+        // mix the seed by a step of splitmix64, so that close seeds give different states
+        let mut z = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+        // state must not be zero
+        Rng(z.max(1))
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    // random number in 0..n
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    fn choose<'a, T>(&mut self, items: &'a [T]) -> Option<&'a T> {
+        if items.is_empty() {
+            None
+        } else {
+            Some(&items[self.below(items.len())])
+        }
+    }
+}
+
+// directory entry (other than "." and "..") reachable from root directory
+struct Entry {
+    dir: usize,
+    block: usize,
+    slot: usize,
+    inode: usize,
+    name: String,
+}
+
+// entries reachable from root directory, in order of slots in each directory
+fn reachable_entries(img: &[u8], sblock: &superblock) -> Vec<Entry> {
+    let mut entries: Vec<Entry> = Vec::new();
+    let mut visited = vec![false; sblock.ninodes as usize];
+    visited[ROOT_INODE] = true;
+    let mut stack: Vec<usize> = vec![ROOT_INODE];
+    while let Some(dir) = stack.pop() {
+        for block in inode_block_numbers(img, extract_inode_pointer_im(img, dir, sblock)) {
+            for (slot, entry) in extract_dirents_pointer_im(img, block).iter().enumerate() {
+                let inode = entry.inum as usize;
+                let name = dirent_name(entry);
+                if inode == 0 || inode >= sblock.ninodes as usize || name == "." || name == ".." {
+                    continue;
+                }
+                if raw_inode_type(img, inode, sblock) == InodeType::T_DIR as i16 && !visited[inode]
+                {
+                    visited[inode] = true;
+                    stack.push(inode);
+                }
+                entries.push(Entry {
+                    dir,
+                    block,
+                    slot,
+                    inode,
+                    name: name.to_string(),
+                });
+            }
+        }
+    }
+    entries
+}
+
+fn is_type(img: &[u8], inode_num: usize, r#type: InodeType, sblock: &superblock) -> bool {
+    raw_inode_type(img, inode_num, sblock) == r#type as i16
+}
+
+// break the image with the corruption of kind, choosing its target by seed
+pub fn corrupt(img: &[u8], kind: &str, seed: u64, sblock: &superblock) -> Result<Record, String> {
+    let mut rng = Rng::new(seed);
+    let entries = reachable_entries(img, sblock);
+    match kind {
+        "free-inode" => free_inode(img, &entries, &mut rng, sblock),
+        "dup-block" => dup_block(img, &entries, &mut rng, sblock),
+        "nlink" => nlink(img, &entries, &mut rng, sblock),
+        "bitmap" => flip_bitmap(img, &mut rng, sblock),
+        "size" => truncate_size(img, &entries, &mut rng, sblock),
+        "dotdot" => dotdot(img, &entries, &mut rng, sblock),
+        _ => Err(format!(
+            "unknown corruption: {} (must be one of {})",
+            kind,
+            KINDS.join(", ")
+        )),
+    }
+}
+
+// point an entry of file at a free inode
+fn free_inode(
+    img: &[u8],
+    entries: &[Entry],
+    rng: &mut Rng,
+    sblock: &superblock,
+) -> Result<Record, String> {
+    let candidates: Vec<&Entry> = entries
+        .iter()
+        .filter(|e| !is_type(img, e.inode, InodeType::T_DIR, sblock))
+        .collect();
+    let free: Vec<usize> = (ROOT_INODE + 1..sblock.ninodes as usize)
+        .filter(|&i| is_type(img, i, InodeType::ZERO, sblock))
+        .collect();
+    let entry = rng.choose(&candidates).ok_or("no file to corrupt")?;
+    let free_inode = *rng.choose(&free).ok_or("no free inode")?;
+
+    extract_dirent_pointer(img, entry.block, entry.slot).inum = free_inode as u16;
+
+    let mut expected = vec![Problem::DirentToFreeInode {
+        dir: entry.dir,
+        inode: free_inode,
+        name: entry.name.clone(),
+    }];
+    let inode = extract_inode_pointer_im(img, entry.inode, sblock);
+    if inode.nlink > 1 {
+        // still reachable through other links
+        expected.push(Problem::WrongNlink {
+            inode: entry.inode,
+            nlink: inode.nlink,
+            expected: inode.nlink - 1,
+        });
+    } else {
+        expected.push(Problem::UnreferencedInode { inode: entry.inode });
+        let mut block_nums = inode_block_numbers(img, inode);
+        if inode.addrs[NDIRECT] != 0 {
+            block_nums.push(inode.addrs[NDIRECT] as usize);
+        }
+        block_nums.sort_unstable();
+        for block in block_nums {
+            expected.push(Problem::FreeBlockMarkedUsed { block });
+        }
+    }
+    Ok(Record {
+        description: format!(
+            "entry \"{}\" of directory inode {} (block {}, slot {}) now refers to free inode {} instead of inode {}",
+            entry.name, entry.dir, entry.block, entry.slot, free_inode, entry.inode
+        ),
+        expected,
+    })
+}
+
+// make a file refer to a block of another file in the same directory
+fn dup_block(
+    img: &[u8],
+    entries: &[Entry],
+    rng: &mut Rng,
+    sblock: &superblock,
+) -> Result<Record, String> {
+    // files with only one link are visited in order of entries, so the first one claims the block
+    let files: Vec<&Entry> = entries
+        .iter()
+        .filter(|e| {
+            let inode = extract_inode_pointer_im(img, e.inode, sblock);
+            is_type(img, e.inode, InodeType::T_FILE, sblock)
+                && inode.nlink == 1
+                && inode.addrs[..NDIRECT].iter().any(|&a| a != 0)
+        })
+        .collect();
+    let mut pairs: Vec<(&Entry, &Entry)> = Vec::new();
+    for (i, a) in files.iter().enumerate() {
+        for b in files[i + 1..].iter().filter(|b| b.dir == a.dir) {
+            pairs.push((a, b));
+        }
+    }
+    let (first, second) = *rng
+        .choose(&pairs)
+        .ok_or("no pair of files with blocks in the same directory")?;
+    let first_blocks = inode_block_numbers(img, extract_inode_pointer_im(img, first.inode, sblock));
+    let block = *rng.choose(&first_blocks).unwrap();
+    let inode: &mut dinode = extract_inode_pointer(img, second.inode, sblock);
+    let indices: Vec<usize> = (0..NDIRECT).filter(|&i| inode.addrs[i] != 0).collect();
+    let index = *rng.choose(&indices).unwrap();
+    let old = inode.addrs[index];
+
+    inode.addrs[index] = block as u32;
+
+    Ok(Record {
+        description: format!(
+            "addrs[{}] of inode {} (\"{}\") now refers to block {} of inode {} (\"{}\") instead of block {}",
+            index, second.inode, second.name, block, first.inode, first.name, old
+        ),
+        expected: vec![
+            Problem::DuplicateBlock {
+                block,
+                inode: second.inode,
+                other: first.inode,
+            },
+            Problem::FreeBlockMarkedUsed {
+                block: old as usize,
+            },
+        ],
+    })
+}
+
+// set wrong nlink to a reachable inode
+fn nlink(
+    img: &[u8],
+    entries: &[Entry],
+    rng: &mut Rng,
+    sblock: &superblock,
+) -> Result<Record, String> {
+    let mut inodes: Vec<usize> = entries.iter().map(|e| e.inode).collect();
+    inodes.push(ROOT_INODE);
+    inodes.sort_unstable();
+    inodes.dedup();
+    let inode_num = *rng.choose(&inodes).unwrap();
+    let inode: &mut dinode = extract_inode_pointer(img, inode_num, sblock);
+    let old = inode.nlink;
+    let new = match rng.below(3) {
+        0 if old > 0 => old - 1,
+        1 => old + 2,
+        _ => old + 1,
+    };
+
+    inode.nlink = new;
+
+    Ok(Record {
+        description: format!(
+            "nlink of inode {} is set to {} (was {})",
+            inode_num, new, old
+        ),
+        expected: vec![Problem::WrongNlink {
+            inode: inode_num,
+            nlink: new,
+            expected: old,
+        }],
+    })
+}
+
+// flip a bit of data region in free bit map
+fn flip_bitmap(img: &[u8], rng: &mut Rng, sblock: &superblock) -> Result<Record, String> {
+    let datastart = sblock::datastart(sblock);
+    let block = datastart + rng.below(sblock.size as usize - datastart);
+    let used = bitmap::check(img, block, sblock);
+
+    bitmap::mark(img, block, !used, sblock);
+
+    Ok(Record {
+        description: format!(
+            "bit of block {} in bitmap is flipped (now marked {})",
+            block,
+            if used { "free" } else { "in use" }
+        ),
+        expected: vec![if used {
+            Problem::UsedBlockMarkedFree { block }
+        } else {
+            Problem::FreeBlockMarkedUsed { block }
+        }],
+    })
+}
+
+// make size of a file smaller than its blocks
+fn truncate_size(
+    img: &[u8],
+    entries: &[Entry],
+    rng: &mut Rng,
+    sblock: &superblock,
+) -> Result<Record, String> {
+    let mut files: Vec<usize> = entries
+        .iter()
+        .filter(|e| is_type(img, e.inode, InodeType::T_FILE, sblock))
+        .filter(|e| {
+            // size must cover some block to be made smaller (it may already be broken)
+            let inode = extract_inode_pointer_im(img, e.inode, sblock);
+            inode.size != 0 && !inode_block_numbers(img, inode).is_empty()
+        })
+        .map(|e| e.inode)
+        .collect();
+    files.sort_unstable();
+    files.dedup();
+    let inode_num = *rng.choose(&files).ok_or("no file with blocks to corrupt")?;
+    let inode: &mut dinode = extract_inode_pointer(img, inode_num, sblock);
+    let old = inode.size;
+    // at least the last block is left beyond new size
+    let nblocks = (old as usize).div_ceil(BLOCK_SIZE);
+    let new = rng.below((nblocks - 1) * BLOCK_SIZE + 1) as u32;
+
+    inode.size = new;
+
+    // same order as fsck checks them
+    let new_nblocks = (new as usize).div_ceil(BLOCK_SIZE);
+    let mut expected: Vec<Problem> = Vec::new();
+    let mut addrs: Vec<u32> = inode.addrs[..NDIRECT].to_vec();
+    let indirect = inode.addrs[NDIRECT] as usize;
+    if indirect != 0 {
+        if new_nblocks <= NDIRECT {
+            expected.push(Problem::BlockBeyondSize {
+                inode: inode_num,
+                size: new,
+                block: indirect,
+            });
+        }
+        addrs.extend(extract_indirect_reference_block_pointer_im(img, indirect).iter());
+    }
+    for (index, &addr) in addrs.iter().enumerate() {
+        if addr != 0 && index >= new_nblocks {
+            expected.push(Problem::BlockBeyondSize {
+                inode: inode_num,
+                size: new,
+                block: addr as usize,
+            });
+        }
+    }
+    Ok(Record {
+        description: format!(
+            "size of inode {} is set to {} (was {})",
+            inode_num, new, old
+        ),
+        expected,
+    })
+}
+
+// point ".." of a directory at a directory other than its parent
+fn dotdot(
+    img: &[u8],
+    entries: &[Entry],
+    rng: &mut Rng,
+    sblock: &superblock,
+) -> Result<Record, String> {
+    let dirs: Vec<&Entry> = entries
+        .iter()
+        .filter(|e| is_type(img, e.inode, InodeType::T_DIR, sblock))
+        .collect();
+    let dir = rng.choose(&dirs).ok_or("no directory other than root")?;
+    let mut targets: Vec<usize> = dirs
+        .iter()
+        .map(|e| e.inode)
+        .chain(Some(ROOT_INODE))
+        .filter(|&i| i != dir.dir)
+        .collect();
+    targets.sort_unstable();
+    let target = *rng.choose(&targets).unwrap();
+    let (_, block, slot) =
+        lookup_dirent(img, extract_inode_pointer_im(img, dir.inode, sblock), "..")
+            .ok_or_else(|| format!("inode {}: directory has no \"..\"", dir.inode))?;
+
+    extract_dirent_pointer(img, block, slot).inum = target as u16;
+
+    let mut expected = vec![Problem::WrongDotDot {
+        inode: dir.inode,
+        points_to: target,
+        parent: dir.dir,
+    }];
+    // parent loses a link, and target (unless it is the directory itself) gains one
+    let mut nlinks: Vec<(usize, i16)> = vec![(dir.dir, -1)];
+    if target != dir.inode {
+        nlinks.push((target, 1));
+    }
+    nlinks.sort_unstable();
+    for (inode_num, delta) in nlinks {
+        let nlink = extract_inode_pointer_im(img, inode_num, sblock).nlink;
+        expected.push(Problem::WrongNlink {
+            inode: inode_num,
+            nlink,
+            expected: nlink + delta,
+        });
+    }
+    Ok(Record {
+        description: format!(
+            "\"..\" of directory inode {} (\"{}\") now refers to inode {} instead of inode {}",
+            dir.inode, dir.name, target, dir.dir
+        ),
+        expected,
+    })
+}
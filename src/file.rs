@@ -21,3 +21,14 @@ pub fn open_new_file(path: &str) -> io::Result<File> {
         .truncate(true)
         .open(path)
 }
+
+pub fn create_image_file(path: &str, len: usize) -> io::Result<File> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)?;
+    file.set_len(len as u64)?;
+    Ok(file)
+}
@@ -0,0 +1,579 @@
+use crate::block::bitmap;
+use crate::block::inode::*;
+use crate::block::sblock;
+use crate::block::sblock::superblock;
+use crate::BLOCK_SIZE;
+
+// inconsistency found by check()
+#[derive(Debug, Clone, PartialEq)]
+pub enum Problem {
+    RootNotDirectory,
+    InvalidType {
+        inode: usize,
+        r#type: i16,
+    },
+    UnreferencedInode {
+        inode: usize,
+    },
+    DirentOutOfRange {
+        dir: usize,
+        inode: usize,
+        name: String,
+    },
+    DirentToFreeInode {
+        dir: usize,
+        inode: usize,
+        name: String,
+    },
+    DirentBeyondSize {
+        dir: usize,
+        inode: usize,
+        name: String,
+        size: u32,
+    },
+    DirectoryLinkedTwice {
+        dir: usize,
+        inode: usize,
+        name: String,
+    },
+    MissingDot {
+        inode: usize,
+    },
+    WrongDot {
+        inode: usize,
+        points_to: usize,
+    },
+    MissingDotDot {
+        inode: usize,
+    },
+    WrongDotDot {
+        inode: usize,
+        points_to: usize,
+        parent: usize,
+    },
+    WrongNlink {
+        inode: usize,
+        nlink: i16,
+        expected: i16,
+    },
+    BadDirectorySize {
+        inode: usize,
+        size: u32,
+    },
+    BlockBeyondSize {
+        inode: usize,
+        size: u32,
+        block: usize,
+    },
+    MissingDirectoryBlock {
+        inode: usize,
+        size: u32,
+        index: usize,
+    },
+    BlockOutOfRange {
+        inode: usize,
+        block: usize,
+    },
+    DuplicateBlock {
+        block: usize,
+        inode: usize,
+        other: usize,
+    },
+    UsedBlockMarkedFree {
+        block: usize,
+    },
+    FreeBlockMarkedUsed {
+        block: usize,
+    },
+}
+
+impl std::fmt::Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use Problem::*;
+        match self {
+            RootNotDirectory => write!(f, "inode {}: root is not a directory", ROOT_INODE),
+            InvalidType { inode, r#type } => write!(f, "inode {}: invalid type {}", inode, r#type),
+            UnreferencedInode { inode } => {
+                write!(f, "inode {}: allocated but not referenced", inode)
+            }
+            DirentOutOfRange { dir, inode, name } => write!(
+                f,
+                "inode {}: entry \"{}\" refers to inode {}, which is out of range",
+                dir, name, inode
+            ),
+            DirentToFreeInode { dir, inode, name } => write!(
+                f,
+                "inode {}: entry \"{}\" refers to free inode {}",
+                dir, name, inode
+            ),
+            DirentBeyondSize {
+                dir,
+                inode,
+                name,
+                size,
+            } => write!(
+                f,
+                "inode {}: entry \"{}\" (inode {}) is beyond directory size {}",
+                dir, name, inode, size
+            ),
+            DirectoryLinkedTwice { dir, inode, name } => write!(
+                f,
+                "inode {}: entry \"{}\" is another link to directory inode {}",
+                dir, name, inode
+            ),
+            MissingDot { inode } => write!(f, "inode {}: directory has no \".\"", inode),
+            WrongDot { inode, points_to } => write!(
+                f,
+                "inode {}: \".\" refers to inode {} instead of itself",
+                inode, points_to
+            ),
+            MissingDotDot { inode } => write!(f, "inode {}: directory has no \"..\"", inode),
+            WrongDotDot {
+                inode,
+                points_to,
+                parent,
+            } => write!(
+                f,
+                "inode {}: \"..\" refers to inode {} instead of parent inode {}",
+                inode, points_to, parent
+            ),
+            WrongNlink {
+                inode,
+                nlink,
+                expected,
+            } => write!(
+                f,
+                "inode {}: nlink is {} but {} links are found",
+                inode, nlink, expected
+            ),
+            BadDirectorySize { inode, size } => write!(
+                f,
+                "inode {}: directory size {} is not a multiple of {}",
+                inode, size, DIRENT_SIZE
+            ),
+            BlockBeyondSize { inode, size, block } => write!(
+                f,
+                "inode {}: block {} is allocated beyond size {}",
+                inode, block, size
+            ),
+            MissingDirectoryBlock { inode, size, index } => write!(
+                f,
+                "inode {}: directory block {} is not allocated within size {}",
+                inode, index, size
+            ),
+            BlockOutOfRange { inode, block } => {
+                write!(f, "inode {}: block {} is out of data region", inode, block)
+            }
+            DuplicateBlock {
+                block,
+                inode,
+                other,
+            } => write!(
+                f,
+                "block {}: referenced by inode {} and inode {}",
+                block, inode, other
+            ),
+            UsedBlockMarkedFree { block } => {
+                write!(f, "block {}: in use but marked free in bitmap", block)
+            }
+            FreeBlockMarkedUsed { block } => {
+                write!(
+                    f,
+                    "block {}: marked in use in bitmap but not referenced",
+                    block
+                )
+            }
+        }
+    }
+}
+
+// references to each block and inode found by walking the tree from root directory
+pub struct Usage {
+    pub block_owner: Vec<Option<usize>>, // inode referring each block
+    pub reachable: Vec<bool>,            // inode is reachable from root
+    pub links: Vec<i16>,                 // number of links to each inode
+}
+
+// walk the tree from root directory, reporting problems of directories and block references
+pub fn walk(img: &[u8], sblock: &superblock, problems: &mut Vec<Problem>) -> Usage {
+    let ninodes = sblock.ninodes as usize;
+    let mut usage = Usage {
+        block_owner: vec![None; sblock.size as usize],
+        reachable: vec![false; ninodes],
+        links: vec![0; ninodes],
+    };
+    if raw_inode_type(img, ROOT_INODE, sblock) != InodeType::T_DIR as i16 {
+        problems.push(Problem::RootNotDirectory);
+        return usage;
+    }
+    // root directory has no entry in parent, but counts its own link like xv6's mkfs
+    usage.links[ROOT_INODE] += 1;
+    usage.reachable[ROOT_INODE] = true;
+
+    // (directory, its parent)
+    let mut stack: Vec<(usize, usize)> = vec![(ROOT_INODE, ROOT_INODE)];
+    while let Some((dir_num, parent_num)) = stack.pop() {
+        let mut has_dot = false;
+        let mut has_dotdot = false;
+        let dir_size = extract_inode_pointer_im(img, dir_num, sblock).size as usize;
+        let block_nums = claim_blocks(img, dir_num, &mut usage, sblock, problems);
+        for (i, block_num) in block_nums.into_iter().enumerate() {
+            for (j, entry) in extract_dirents_pointer_im(img, block_num)
+                .iter()
+                .enumerate()
+            {
+                if entry.inum == 0 {
+                    continue;
+                }
+                let inode_num = entry.inum as usize;
+                let name = String::from_utf8_lossy(&entry.name)
+                    .trim_matches(char::from(0))
+                    .to_string();
+                if i * BLOCK_SIZE + j * DIRENT_SIZE >= dir_size {
+                    // kernel does not see this entry
+                    problems.push(Problem::DirentBeyondSize {
+                        dir: dir_num,
+                        inode: inode_num,
+                        name: name.clone(),
+                        size: dir_size as u32,
+                    });
+                }
+                if inode_num >= ninodes {
+                    problems.push(Problem::DirentOutOfRange {
+                        dir: dir_num,
+                        inode: inode_num,
+                        name,
+                    });
+                    continue;
+                }
+                let r#type = raw_inode_type(img, inode_num, sblock);
+                if r#type == InodeType::ZERO as i16 {
+                    problems.push(Problem::DirentToFreeInode {
+                        dir: dir_num,
+                        inode: inode_num,
+                        name,
+                    });
+                    continue;
+                }
+                if name == "." {
+                    has_dot = true;
+                    if inode_num != dir_num {
+                        problems.push(Problem::WrongDot {
+                            inode: dir_num,
+                            points_to: inode_num,
+                        });
+                    }
+                    continue;
+                }
+                if name == ".." {
+                    has_dotdot = true;
+                    if inode_num != parent_num {
+                        problems.push(Problem::WrongDotDot {
+                            inode: dir_num,
+                            points_to: inode_num,
+                            parent: parent_num,
+                        });
+                    }
+                    // ".." of subdirectory is a link to its parent
+                    if inode_num != dir_num {
+                        usage.links[inode_num] += 1;
+                    }
+                    continue;
+                }
+
+                usage.links[inode_num] += 1;
+                if r#type < InodeType::T_DIR as i16 || r#type > InodeType::T_DEV as i16 {
+                    // reported by check()
+                    usage.reachable[inode_num] = true;
+                    continue;
+                }
+                if r#type == InodeType::T_DIR as i16 {
+                    if usage.reachable[inode_num] {
+                        problems.push(Problem::DirectoryLinkedTwice {
+                            dir: dir_num,
+                            inode: inode_num,
+                            name,
+                        });
+                        continue;
+                    }
+                    usage.reachable[inode_num] = true;
+                    stack.push((inode_num, dir_num));
+                } else if !usage.reachable[inode_num] {
+                    usage.reachable[inode_num] = true;
+                    claim_blocks(img, inode_num, &mut usage, sblock, problems);
+                }
+            }
+        }
+        if !has_dot {
+            problems.push(Problem::MissingDot { inode: dir_num });
+        }
+        if !has_dotdot {
+            problems.push(Problem::MissingDotDot { inode: dir_num });
+        }
+    }
+    usage
+}
+
+// record blocks of the inode as used by it, and return its data blocks which can be read
+fn claim_blocks(
+    img: &[u8],
+    inode_num: usize,
+    usage: &mut Usage,
+    sblock: &superblock,
+    problems: &mut Vec<Problem>,
+) -> Vec<usize> {
+    let inode = extract_inode_pointer_im(img, inode_num, sblock);
+    let datastart = sblock::datastart(sblock);
+    let mut claim = |block_num: usize, problems: &mut Vec<Problem>| -> bool {
+        if block_num < datastart || block_num >= sblock.size as usize {
+            problems.push(Problem::BlockOutOfRange {
+                inode: inode_num,
+                block: block_num,
+            });
+            return false;
+        }
+        if let Some(other) = usage.block_owner[block_num] {
+            problems.push(Problem::DuplicateBlock {
+                block: block_num,
+                inode: inode_num,
+                other,
+            });
+            return false;
+        }
+        usage.block_owner[block_num] = Some(inode_num);
+        true
+    };
+
+    let mut block_nums: Vec<usize> = Vec::new();
+    for &addr in inode.addrs[..NDIRECT].iter().filter(|&&a| a != 0) {
+        if claim(addr as usize, problems) {
+            block_nums.push(addr as usize);
+        }
+    }
+    let indirect = inode.addrs[NDIRECT] as usize;
+    if indirect != 0 && claim(indirect, problems) {
+        for &addr in extract_indirect_reference_block_pointer_im(img, indirect)
+            .iter()
+            .filter(|&&a| a != 0)
+        {
+            if claim(addr as usize, problems) {
+                block_nums.push(addr as usize);
+            }
+        }
+    }
+    block_nums
+}
+
+// check size of the inode against blocks allocated to it
+fn check_size(img: &[u8], inode_num: usize, sblock: &superblock, problems: &mut Vec<Problem>) {
+    let inode = extract_inode_pointer_im(img, inode_num, sblock);
+    let size = inode.size;
+    let nblocks = (size as usize).div_ceil(BLOCK_SIZE);
+    let is_dir = inode.r#type == InodeType::T_DIR;
+    if is_dir && size as usize % DIRENT_SIZE != 0 {
+        problems.push(Problem::BadDirectorySize {
+            inode: inode_num,
+            size,
+        });
+    }
+
+    let mut addrs: Vec<u32> = inode.addrs[..NDIRECT].to_vec();
+    let indirect = inode.addrs[NDIRECT] as usize;
+    if indirect != 0 {
+        if nblocks <= NDIRECT {
+            problems.push(Problem::BlockBeyondSize {
+                inode: inode_num,
+                size,
+                block: indirect,
+            });
+        }
+        if indirect >= sblock::datastart(sblock) && indirect < sblock.size as usize {
+            addrs.extend(extract_indirect_reference_block_pointer_im(img, indirect).iter());
+        }
+    }
+    for (index, &addr) in addrs.iter().enumerate() {
+        if addr != 0 && index >= nblocks {
+            problems.push(Problem::BlockBeyondSize {
+                inode: inode_num,
+                size,
+                block: addr as usize,
+            });
+        }
+        if addr == 0 && index < nblocks && is_dir {
+            problems.push(Problem::MissingDirectoryBlock {
+                inode: inode_num,
+                size,
+                index,
+            });
+        }
+    }
+}
+
+// check consistency of the whole image, and return problems found
+pub fn check(img: &[u8], sblock: &superblock) -> Vec<Problem> {
+    let mut problems: Vec<Problem> = Vec::new();
+    let usage = walk(img, sblock, &mut problems);
+
+    for inode_num in ROOT_INODE..sblock.ninodes as usize {
+        let r#type = raw_inode_type(img, inode_num, sblock);
+        if r#type == InodeType::ZERO as i16 {
+            continue;
+        }
+        if r#type < InodeType::T_DIR as i16 || r#type > InodeType::T_DEV as i16 {
+            problems.push(Problem::InvalidType {
+                inode: inode_num,
+                r#type,
+            });
+            continue;
+        }
+        if !usage.reachable[inode_num] {
+            problems.push(Problem::UnreferencedInode { inode: inode_num });
+            continue;
+        }
+        let nlink = extract_inode_pointer_im(img, inode_num, sblock).nlink;
+        if nlink != usage.links[inode_num] {
+            problems.push(Problem::WrongNlink {
+                inode: inode_num,
+                nlink,
+                expected: usage.links[inode_num],
+            });
+        }
+        check_size(img, inode_num, sblock, &mut problems);
+    }
+
+    // metadata blocks are always in use
+    let datastart = sblock::datastart(sblock);
+    for block_num in 0..sblock.size as usize {
+        let used = block_num < datastart || usage.block_owner[block_num].is_some();
+        let marked = bitmap::check(img, block_num, sblock);
+        if used && !marked {
+            problems.push(Problem::UsedBlockMarkedFree { block: block_num });
+        } else if !used && marked {
+            problems.push(Problem::FreeBlockMarkedUsed { block: block_num });
+        }
+    }
+    problems
+}
+
+// fix what can be fixed from the tree reachable from root directory, and return what is changed:
+// entries referring free inodes are cleared, unreferenced inodes are released,
+// nlink and directory size are recomputed, and free bit map is rebuilt.
+// nothing is changed if root is not a directory, as nothing would be found reachable.
+pub fn repair(img: &mut [u8], sblock: &superblock) -> Result<Vec<String>, String> {
+    let mut changes: Vec<String> = Vec::new();
+    let mut problems: Vec<Problem> = Vec::new();
+
+    // entries referring free inodes
+    walk(img, sblock, &mut problems);
+    if problems.contains(&Problem::RootNotDirectory) {
+        return Err(format!(
+            "root (inode {}) is not a directory (set its type first)",
+            ROOT_INODE
+        ));
+    }
+    for dir in problems.iter().filter_map(|p| match p {
+        Problem::DirentOutOfRange { dir, .. } | Problem::DirentToFreeInode { dir, .. } => {
+            Some(*dir)
+        }
+        _ => None,
+    }) {
+        for block_num in inode_block_numbers(img, extract_inode_pointer_im(img, dir, sblock)) {
+            for entry in extract_dirents_pointer(img, block_num).iter_mut() {
+                let inode_num = entry.inum as usize;
+                if inode_num == 0
+                    || (inode_num < sblock.ninodes as usize
+                        && raw_inode_type(img, inode_num, sblock) != InodeType::ZERO as i16)
+                {
+                    continue;
+                }
+                changes.push(format!(
+                    "inode {}: cleared entry \"{}\" referring free inode {}",
+                    dir,
+                    dirent_name(entry),
+                    inode_num
+                ));
+                *entry = dirent {
+                    inum: 0,
+                    name: [0u8; DIRSIZ],
+                };
+            }
+        }
+    }
+
+    // ".." pointing to other than the parent found by walking the tree
+    problems.clear();
+    walk(img, sblock, &mut problems);
+    for (dir, parent) in problems.iter().filter_map(|p| match p {
+        Problem::WrongDotDot { inode, parent, .. } => Some((*inode, *parent)),
+        _ => None,
+    }) {
+        let dir_inode = extract_inode_pointer_im(img, dir, sblock);
+        if let Some((points_to, block_num, j)) = lookup_dirent(img, dir_inode, "..") {
+            extract_dirents_pointer(img, block_num)[j].inum = parent as u16;
+            changes.push(format!("inode {}: \"..\" {} -> {}", dir, points_to, parent));
+        }
+    }
+
+    problems.clear();
+    let usage = walk(img, sblock, &mut problems);
+    for inode_num in ROOT_INODE..sblock.ninodes as usize {
+        let r#type = raw_inode_type(img, inode_num, sblock);
+        if r#type == InodeType::ZERO as i16 {
+            continue;
+        }
+        if !usage.reachable[inode_num] && inode_num != ROOT_INODE {
+            // its blocks are released by rebuilding bit map below
+            clear_inode(img, inode_num, sblock);
+            changes.push(format!("inode {}: released (not referenced)", inode_num));
+            continue;
+        }
+        if r#type < InodeType::T_DIR as i16 || r#type > InodeType::T_DEV as i16 {
+            continue;
+        }
+        let inode: &mut dinode = extract_inode_pointer(img, inode_num, sblock);
+        if inode.nlink != usage.links[inode_num] {
+            changes.push(format!(
+                "inode {}: nlink {} -> {}",
+                inode_num, inode.nlink, usage.links[inode_num]
+            ));
+            inode.nlink = usage.links[inode_num];
+        }
+        if inode.r#type == InodeType::T_DIR {
+            // size covers every block of directory
+            let nblocks = inode_block_numbers(img, inode).len();
+            let size = inode.size as usize;
+            if size.div_ceil(BLOCK_SIZE) != nblocks || size % DIRENT_SIZE != 0 {
+                changes.push(format!(
+                    "inode {}: size {} -> {}",
+                    inode_num,
+                    size,
+                    nblocks * BLOCK_SIZE
+                ));
+                inode.size = (nblocks * BLOCK_SIZE) as u32;
+            }
+        }
+    }
+
+    // rebuild free bit map
+    let datastart = sblock::datastart(sblock);
+    let mut marked_used = 0;
+    let mut marked_free = 0;
+    for block_num in 0..sblock.size as usize {
+        let used = block_num < datastart || usage.block_owner[block_num].is_some();
+        if used != bitmap::check(img, block_num, sblock) {
+            bitmap::switch(img, block_num, sblock);
+            if used {
+                marked_used += 1;
+            } else {
+                marked_free += 1;
+            }
+        }
+    }
+    if marked_used != 0 || marked_free != 0 {
+        changes.push(format!(
+            "bitmap: {} blocks marked used, {} blocks marked free",
+            marked_used, marked_free
+        ));
+    }
+    Ok(changes)
+}
@@ -3,5 +3,7 @@ extern crate memmap;
 pub const BLOCK_SIZE: usize = 1024;
 
 pub mod block;
+pub mod corrupt;
 pub mod file;
+pub mod fsck;
 pub mod subcommand;
@@ -1,21 +1,120 @@
 #[macro_use]
 extern crate clap;
 
-use clap::{AppSettings, Arg, SubCommand};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use memmap::MmapMut;
+use opfs::block::log;
+use opfs::block::record;
 use opfs::block::sblock;
+use opfs::block::sblock::superblock;
 use opfs::file::*;
 use opfs::subcommand;
+use std::ffi::{OsStr, OsString};
+use std::path::Path;
 use std::process::exit;
 
 fn main() {
-    let matches = app_from_crate!()
+    // invoked as `mkfs` (e.g. through symbolic link), work as drop-in replacement of xv6's mkfs
+    let mut args: Vec<OsString> = std::env::args_os().collect();
+    if !args.is_empty() && Path::new(&args[0]).file_stem() == Some(OsStr::new("mkfs")) {
+        args.insert(1, OsString::from("mkfs"));
+    }
+
+    let matches = app().get_matches_from(args);
+
+    if let Some(ref sub_matches) = matches.subcommand_matches("mkfs") {
+        if matches.is_present("img_file") {
+            eprintln!("mkfs: image file must be given after subcommand: opfs mkfs img_file");
+            exit(1);
+        }
+        let path = sub_matches.value_of("img_file").unwrap();
+        let size = value_t_or_exit!(sub_matches, "size", u32);
+        let ninodes = value_t_or_exit!(sub_matches, "ninodes", u32);
+        let nlog = value_t_or_exit!(sub_matches, "nlog", u32);
+        let files: Vec<&str> = sub_matches
+            .values_of("files")
+            .map(|v| v.collect())
+            .unwrap_or_default();
+        subcommand::mkfs(path, size, ninodes, nlog, &files);
+        return;
+    }
+
+    let path = match matches.value_of("img_file") {
+        Some(p) => p,
+        None => {
+            eprintln!("error: img_file is required\n\n{}", matches.usage());
+            exit(1);
+        }
+    };
+    let file_size = match get_file_size(path) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        }
+    };
+    let file = match open_readable_and_writable_file(path) {
+        Ok(f) => f,
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        }
+    };
+    let mut img = match get_memory_mapped_file(&file, file_size) {
+        Ok(m) => m,
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        }
+    };
+    let sblock = sblock::u8_slice_as_superblock(&img);
+    // set-super can fix broken super block, so it must not be refused
+    let raw_super = matches.subcommand_matches("set-super").is_some();
+    if !raw_super {
+        sblock::check_magic_number(&sblock);
+    }
+
+    if matches.subcommand_matches("log").is_none() && !raw_super {
+        match log::read_header(&img, &sblock) {
+            Ok(ref v) if v.is_empty() => {}
+            Ok(v) => eprintln!(
+                "warning: log has {} committed blocks which are not installed (run `log replay` to install them)",
+                v.len()
+            ),
+            Err(e) => eprintln!("warning: {}", e),
+        }
+    }
+
+    if matches.is_present("transaction") {
+        // run on a copy of image, and write changed blocks through log
+        let mut work = copy_image(&img);
+        dispatch(&matches, &mut work, &sblock);
+        if let Err(e) = log::commit(&mut img, &work, &sblock) {
+            eprintln!("error: transaction is aborted: {}", e);
+            exit(1);
+        }
+    } else {
+        dispatch(&matches, &mut img, &sblock);
+    }
+}
+
+fn app<'a, 'b>() -> App<'a, 'b> {
+    app_from_crate!()
         .setting(AppSettings::SubcommandRequiredElseHelp)
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .usage("opfs [-t] <img_file> <SUBCOMMAND>\n    opfs mkfs <img_file> [OPTIONS]")
         .arg(
             Arg::with_name("img_file")
                 .help("path to image file to manipulate")
                 .required(true)
                 .index(1),
         )
+        .arg(
+            Arg::with_name("transaction")
+                .help("write changes through log of image file, like xv6 kernel does")
+                .short("t")
+                .long("transaction"),
+        )
         .subcommand(
             SubCommand::with_name("ls")
                 .about("list directory contents")
@@ -29,6 +128,12 @@ fn main() {
         .subcommand(
             SubCommand::with_name("get")
                 .about("extract file")
+                .arg(
+                    Arg::with_name("recursive")
+                        .help("extract directory and its contents recursively")
+                        .short("r")
+                        .long("recursive"),
+                )
                 .arg(
                     Arg::with_name("source")
                         .help("path to file to extract")
@@ -45,6 +150,12 @@ fn main() {
         .subcommand(
             SubCommand::with_name("rm")
                 .about("remove directory entries")
+                .arg(
+                    Arg::with_name("recursive")
+                        .help("remove directories and their contents recursively")
+                        .short("r")
+                        .long("recursive"),
+                )
                 .arg(
                     Arg::with_name("path")
                         .help("path to file to remove")
@@ -55,6 +166,18 @@ fn main() {
         .subcommand(
             SubCommand::with_name("put")
                 .about("put file into image file")
+                .arg(
+                    Arg::with_name("recursive")
+                        .help("put directory and its contents recursively")
+                        .short("r")
+                        .long("recursive"),
+                )
+                .arg(
+                    Arg::with_name("force")
+                        .help("overwrite existing file, keeping its inode")
+                        .short("f")
+                        .long("force"),
+                )
                 .arg(
                     Arg::with_name("source")
                         .help("path to file to put (host)")
@@ -68,45 +191,551 @@ fn main() {
                         .index(2),
                 ),
         )
-        .get_matches();
-    let path = matches.value_of("img_file").unwrap();
-    let file_size = match get_file_size(path) {
-        Ok(s) => s,
-        Err(e) => {
-            eprintln!("{}", e);
-            exit(1);
-        }
-    };
-    let file = match open_readable_and_writable_file(path) {
-        Ok(f) => f,
-        Err(e) => {
-            eprintln!("{}", e);
-            exit(1);
-        }
-    };
-    let mut img = match get_memory_mapped_file(&file, file_size) {
+        .subcommand(
+            SubCommand::with_name("mkdir")
+                .about("make directory")
+                .arg(
+                    Arg::with_name("parents")
+                        .help("make parent directories as needed")
+                        .short("p")
+                        .long("parents"),
+                )
+                .arg(
+                    Arg::with_name("path")
+                        .help("path to directory to make")
+                        .required(true)
+                        .index(1),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("rmdir")
+                .about("remove empty directory")
+                .arg(
+                    Arg::with_name("path")
+                        .help("path to directory to remove")
+                        .required(true)
+                        .index(1),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("compact")
+                .about("pack directory entries and release empty blocks")
+                .arg(
+                    Arg::with_name("sort")
+                        .help("sort entries by name")
+                        .short("s")
+                        .long("sort"),
+                )
+                .arg(
+                    Arg::with_name("path")
+                        .help("path to directory to compact")
+                        .required(true)
+                        .index(1),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("mknod")
+                .about("make device file")
+                .arg(
+                    Arg::with_name("path")
+                        .help("path to device file to make")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("major")
+                        .help("major device number")
+                        .required(true)
+                        .index(2),
+                )
+                .arg(
+                    Arg::with_name("minor")
+                        .help("minor device number")
+                        .required(true)
+                        .index(3),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("touch")
+                .about("make empty file if it does not exist")
+                .arg(
+                    Arg::with_name("path")
+                        .help("path to file")
+                        .required(true)
+                        .index(1),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("truncate")
+                .about("shrink or extend file to the size")
+                .arg(
+                    Arg::with_name("size")
+                        .help("new size in bytes")
+                        .short("s")
+                        .long("size")
+                        .takes_value(true)
+                        .value_name("N")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("sparse")
+                        .help("leave holes instead of allocating zeroed blocks when extending")
+                        .long("sparse"),
+                )
+                .arg(
+                    Arg::with_name("path")
+                        .help("path to file")
+                        .required(true)
+                        .index(1),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("write")
+                .about("write contents of host file into file in image file")
+                .arg(
+                    Arg::with_name("offset")
+                        .help("byte offset to start writing at (default: 0)")
+                        .short("o")
+                        .long("offset")
+                        .takes_value(true)
+                        .value_name("N"),
+                )
+                .arg(
+                    Arg::with_name("append")
+                        .help("write at the end of file")
+                        .short("a")
+                        .long("append")
+                        .conflicts_with("offset"),
+                )
+                .arg(
+                    Arg::with_name("source")
+                        .help("path to file in host (\"-\" for stdin)")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("destination")
+                        .help("path to file in image file")
+                        .required(true)
+                        .index(2),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("ln")
+                .about("make hard link")
+                .arg(
+                    Arg::with_name("source")
+                        .help("path to existing file")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("destination")
+                        .help("path to new link")
+                        .required(true)
+                        .index(2),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("mv")
+                .about("move (rename) file")
+                .arg(
+                    Arg::with_name("source")
+                        .help("path to file to move")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("destination")
+                        .help("destination path (or directory to move into)")
+                        .required(true)
+                        .index(2),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("cp")
+                .about("copy file within image file")
+                .arg(
+                    Arg::with_name("recursive")
+                        .help("copy directories recursively")
+                        .short("r")
+                        .long("recursive"),
+                )
+                .arg(
+                    Arg::with_name("source")
+                        .help("path to file to copy")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("destination")
+                        .help("destination path (or directory to copy into)")
+                        .required(true)
+                        .index(2),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("fsck")
+                .about("check consistency of image file")
+                .arg(
+                    Arg::with_name("repair")
+                        .help("rebuild bitmap, link counts and directory sizes")
+                        .long("repair"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("diskinfo").about("show super block and layout of image file"),
+        )
+        .subcommand(
+            SubCommand::with_name("info")
+                .about("show inode details and block addresses")
+                .arg(
+                    Arg::with_name("inode")
+                        .help("inode number instead of path")
+                        .short("i")
+                        .long("inode")
+                        .takes_value(true)
+                        .value_name("N")
+                        .conflicts_with("path"),
+                )
+                .arg(
+                    Arg::with_name("path")
+                        .help("path to file")
+                        .required_unless("inode")
+                        .index(1),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("log")
+                .about("show header of log")
+                .subcommand(
+                    SubCommand::with_name("replay")
+                        .about("install committed blocks in log and clear its header"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("mkfs")
+                .about("make new file system image (usage: opfs mkfs img_file)")
+                .arg(
+                    Arg::with_name("img_file")
+                        .help("path to image file to create")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("files")
+                        .help("files to put into root directory (same as xv6's mkfs)")
+                        .multiple(true)
+                        .index(2),
+                )
+                .arg(
+                    Arg::with_name("size")
+                        .help("size of file system in blocks")
+                        .long("size")
+                        .takes_value(true)
+                        .default_value("2000"),
+                )
+                .arg(
+                    Arg::with_name("ninodes")
+                        .help("number of inodes")
+                        .long("ninodes")
+                        .takes_value(true)
+                        .default_value("200"),
+                )
+                .arg(
+                    Arg::with_name("nlog")
+                        .help("number of log blocks")
+                        .long("nlog")
+                        .takes_value(true)
+                        .default_value("30"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("corrupt")
+                .about("break image file on purpose (for exercises of fsck)")
+                .arg(
+                    Arg::with_name("seed")
+                        .help("seed to choose target of corruption (default: current time)")
+                        .short("s")
+                        .long("seed")
+                        .takes_value(true)
+                        .value_name("N"),
+                )
+                .arg(
+                    Arg::with_name("kind")
+                        .help("kind of corruption")
+                        .required(true)
+                        .possible_values(&opfs::corrupt::KINDS)
+                        .index(1),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("set-super")
+                .about("set field of super block (no check is done)")
+                .arg(
+                    Arg::with_name("field")
+                        .help("field to set")
+                        .required(true)
+                        .possible_values(&subcommand::SUPERBLOCK_FIELDS)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("value")
+                        .help("new value (decimal, or hexadecimal with 0x)")
+                        .required(true)
+                        .index(2),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("set-inode")
+                .about("set field of inode (no check is done)")
+                .setting(AppSettings::AllowNegativeNumbers)
+                .arg(
+                    Arg::with_name("inode")
+                        .help("inode number")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("field")
+                        .help("field to set (type, major, minor, nlink, size or addrs[i])")
+                        .required(true)
+                        .index(2),
+                )
+                .arg(
+                    Arg::with_name("value")
+                        .help("new value (decimal, or hexadecimal with 0x)")
+                        .required(true)
+                        .index(3),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("set-dirent")
+                .about("set directory entry (no check is done)")
+                .arg(
+                    Arg::with_name("dir")
+                        .help("inode number of directory")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("slot")
+                        .help("index of entry in directory")
+                        .required(true)
+                        .index(2),
+                )
+                .arg(
+                    Arg::with_name("inode")
+                        .help("inode number the entry refers to")
+                        .required(true)
+                        .index(3),
+                )
+                .arg(
+                    Arg::with_name("name")
+                        .help("name of the entry")
+                        .required(true)
+                        .index(4),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("bitmap")
+                .about("set or clear bit of block in bitmap (no check is done)")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("set")
+                        .about("mark block as used")
+                        .arg(Arg::with_name("block").required(true).index(1)),
+                )
+                .subcommand(
+                    SubCommand::with_name("clear")
+                        .about("mark block as free")
+                        .arg(Arg::with_name("block").required(true).index(1)),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("crashtest")
+                .about("check consistency of image crashed at every block write of the command")
+                .setting(AppSettings::TrailingVarArg)
+                .arg(
+                    Arg::with_name("log")
+                        .help("write blocks through log, as transactional mode (-t) does")
+                        .short("l")
+                        .long("log"),
+                )
+                .arg(
+                    Arg::with_name("recover")
+                        .help("replay log of crashed image before checking it")
+                        .short("r")
+                        .long("recover"),
+                )
+                .arg(
+                    Arg::with_name("command")
+                        .help("subcommand to run, with its arguments")
+                        .required(true)
+                        .multiple(true)
+                        .index(1),
+                ),
+        )
+}
+
+// anonymous (not backed by the file) copy of image
+fn copy_image(img: &MmapMut) -> MmapMut {
+    let mut copy = match MmapMut::map_anon(img.len()) {
         Ok(m) => m,
         Err(e) => {
-            eprintln!("{}", e);
+            eprintln!("error: {}", e);
             exit(1);
         }
     };
-    let sblock = sblock::u8_slice_as_superblock(&img);
-    sblock::check_magic_number(&sblock);
+    copy.copy_from_slice(img);
+    copy
+}
 
+fn dispatch(matches: &ArgMatches, img: &mut MmapMut, sblock: &superblock) {
     if let Some(ref matches) = matches.subcommand_matches("ls") {
         let path = matches.value_of("path").unwrap();
-        subcommand::ls(&img, &path, &sblock);
+        subcommand::ls(img, path, sblock);
     } else if let Some(ref matches) = matches.subcommand_matches("get") {
         let src = matches.value_of("source").unwrap();
         let dst = matches.value_of("destination").unwrap();
-        subcommand::get(&img, &src, &dst, &sblock);
+        let recursive = matches.is_present("recursive");
+        subcommand::get(img, src, dst, recursive, sblock);
     } else if let Some(ref matches) = matches.subcommand_matches("rm") {
         let path = matches.value_of("path").unwrap();
-        subcommand::rm(&mut img, &path, &sblock);
+        let recursive = matches.is_present("recursive");
+        subcommand::rm(img, path, recursive, sblock);
     } else if let Some(ref matches) = matches.subcommand_matches("put") {
         let src = matches.value_of("source").unwrap();
         let dst = matches.value_of("destination").unwrap();
-        subcommand::put(&mut img, &src, &dst, &sblock);
+        let recursive = matches.is_present("recursive");
+        let force = matches.is_present("force");
+        subcommand::put(img, src, dst, recursive, force, sblock);
+    } else if let Some(ref matches) = matches.subcommand_matches("mkdir") {
+        let path = matches.value_of("path").unwrap();
+        let parents = matches.is_present("parents");
+        subcommand::mkdir(img, path, parents, sblock);
+    } else if let Some(ref matches) = matches.subcommand_matches("rmdir") {
+        let path = matches.value_of("path").unwrap();
+        subcommand::rmdir(img, path, sblock);
+    } else if let Some(ref matches) = matches.subcommand_matches("compact") {
+        let path = matches.value_of("path").unwrap();
+        let sorted = matches.is_present("sort");
+        subcommand::compact(img, path, sorted, sblock);
+    } else if let Some(ref matches) = matches.subcommand_matches("mknod") {
+        let path = matches.value_of("path").unwrap();
+        let major = value_t_or_exit!(matches, "major", i16);
+        let minor = value_t_or_exit!(matches, "minor", i16);
+        subcommand::mknod(img, path, major, minor, sblock);
+    } else if let Some(ref matches) = matches.subcommand_matches("touch") {
+        let path = matches.value_of("path").unwrap();
+        subcommand::touch(img, path, sblock);
+    } else if let Some(ref matches) = matches.subcommand_matches("truncate") {
+        let path = matches.value_of("path").unwrap();
+        let size = value_t_or_exit!(matches, "size", usize);
+        let sparse = matches.is_present("sparse");
+        subcommand::truncate(img, path, size, sparse, sblock);
+    } else if let Some(ref matches) = matches.subcommand_matches("write") {
+        let src = matches.value_of("source").unwrap();
+        let dst = matches.value_of("destination").unwrap();
+        let offset = if matches.is_present("offset") {
+            value_t_or_exit!(matches, "offset", usize)
+        } else {
+            0
+        };
+        let append = matches.is_present("append");
+        subcommand::write(img, src, dst, offset, append, sblock);
+    } else if let Some(ref matches) = matches.subcommand_matches("ln") {
+        let src = matches.value_of("source").unwrap();
+        let dst = matches.value_of("destination").unwrap();
+        subcommand::ln(img, src, dst, sblock);
+    } else if let Some(ref matches) = matches.subcommand_matches("mv") {
+        let src = matches.value_of("source").unwrap();
+        let dst = matches.value_of("destination").unwrap();
+        subcommand::mv(img, src, dst, sblock);
+    } else if let Some(ref matches) = matches.subcommand_matches("cp") {
+        let src = matches.value_of("source").unwrap();
+        let dst = matches.value_of("destination").unwrap();
+        let recursive = matches.is_present("recursive");
+        subcommand::cp(img, src, dst, recursive, sblock);
+    } else if let Some(ref matches) = matches.subcommand_matches("fsck") {
+        let repair = matches.is_present("repair");
+        subcommand::fsck(img, repair, sblock);
+    } else if matches.subcommand_matches("diskinfo").is_some() {
+        subcommand::diskinfo(img, sblock);
+    } else if let Some(ref matches) = matches.subcommand_matches("log") {
+        let replay = matches.subcommand_matches("replay").is_some();
+        subcommand::log(img, replay, sblock);
+    } else if let Some(ref matches) = matches.subcommand_matches("corrupt") {
+        let kind = matches.value_of("kind").unwrap();
+        let seed = if matches.is_present("seed") {
+            Some(value_t_or_exit!(matches, "seed", u64))
+        } else {
+            None
+        };
+        subcommand::corrupt(img, kind, seed, sblock);
+    } else if let Some(ref matches) = matches.subcommand_matches("set-super") {
+        let field = matches.value_of("field").unwrap();
+        let value = matches.value_of("value").unwrap();
+        subcommand::set_super(img, field, value);
+    } else if let Some(ref matches) = matches.subcommand_matches("set-inode") {
+        let inode_num = value_t_or_exit!(matches, "inode", usize);
+        let field = matches.value_of("field").unwrap();
+        let value = matches.value_of("value").unwrap();
+        subcommand::set_inode(img, inode_num, field, value, sblock);
+    } else if let Some(ref matches) = matches.subcommand_matches("set-dirent") {
+        let dir_inode_num = value_t_or_exit!(matches, "dir", usize);
+        let slot = value_t_or_exit!(matches, "slot", usize);
+        let inode_num = value_t_or_exit!(matches, "inode", u16);
+        let name = matches.value_of("name").unwrap();
+        subcommand::set_dirent(img, dir_inode_num, slot, inode_num, name, sblock);
+    } else if let Some(ref matches) = matches.subcommand_matches("bitmap") {
+        let (used, matches) = match matches.subcommand() {
+            ("set", Some(m)) => (true, m),
+            (_, Some(m)) => (false, m),
+            _ => unreachable!(),
+        };
+        let block_num = value_t_or_exit!(matches, "block", usize);
+        subcommand::bitmap(img, block_num, used, sblock);
+    } else if let Some(ref matches) = matches.subcommand_matches("crashtest") {
+        let log = matches.is_present("log");
+        let recover = matches.is_present("recover");
+        // image file is not used by dispatch, so anything can be given
+        let command = ["opfs", "img_file"]
+            .iter()
+            .copied()
+            .chain(matches.values_of("command").unwrap());
+        let inner = app()
+            .get_matches_from_safe(command)
+            .unwrap_or_else(|e| e.exit());
+        match inner.subcommand_name() {
+            Some("crashtest") | Some("mkfs") => {
+                eprintln!(
+                    "crashtest: cannot test {}",
+                    inner.subcommand_name().unwrap()
+                );
+                exit(1);
+            }
+            _ => {}
+        }
+        let mut work = copy_image(img);
+        // without log, blocks are written in the order the command writes them
+        if !log {
+            record::start(&work);
+        }
+        dispatch(&inner, &mut work, sblock);
+        let recorded = if log {
+            None
+        } else {
+            Some(record::finish(&work))
+        };
+        subcommand::crashtest(img, &work, recorded, recover, sblock);
+    } else if let Some(ref matches) = matches.subcommand_matches("info") {
+        let path = matches.value_of("path");
+        let inode_num = if matches.is_present("inode") {
+            value_t_or_exit!(matches, "inode", usize)
+        } else {
+            0
+        };
+        subcommand::info(img, path, inode_num, sblock);
     }
 }
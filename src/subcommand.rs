@@ -1,8 +1,13 @@
+use crate::block::bitmap;
 use crate::block::inode::dinode;
 use crate::block::inode::*;
+use crate::block::log;
+use crate::block::record;
+use crate::block::sblock;
 use crate::block::sblock::superblock;
 use crate::BLOCK_SIZE;
 use memmap::MmapMut;
+use std::path::Path;
 use std::process::exit;
 use std::str::from_utf8;
 
@@ -14,80 +19,94 @@ pub fn ls(img: &MmapMut, path: &str, sblock: &superblock) {
     }
     let (inode, inode_num): (&dinode, usize) = inode.unwrap();
 
-    match inode.r#type {
-        InodeType::T_DIR => {
-            for i in 0..NDIRECT {
-                if inode.addrs[i] == 0 {
-                    break;
-                }
-                for entry in extract_dirents_pointer_im(&img, inode.addrs[i] as usize).into_iter() {
-                    let name = from_utf8(&entry.name).unwrap().trim_matches(char::from(0));
-                    if name.is_empty() {
-                        continue;
-                    }
-                    let inode = extract_inode_pointer_im(&img, entry.inum.into(), &sblock);
-                    println!(
-                        "{:<width$}: {}, No.{}, {} Bytes",
-                        name,
-                        inode.r#type,
-                        entry.inum,
-                        inode.size,
-                        width = DIRSIZ
-                    );
-                }
-            }
-            if inode.addrs[NDIRECT] != 0 {
-                // indirect reference block
-                for i in
-                    extract_indirect_reference_block_pointer_im(&img, inode.addrs[NDIRECT] as usize)
-                        .into_iter()
-                {
-                    if *i == 0u32 {
-                        break;
-                    }
-                    for entry in extract_dirents_pointer_im(&img, (*i) as usize).into_iter() {
-                        let name = from_utf8(&entry.name).unwrap().trim_matches(char::from(0));
-                        if name.is_empty() {
-                            continue;
-                        }
-                        let inode = extract_inode_pointer_im(&img, entry.inum.into(), &sblock);
-                        println!(
-                            "{:<width$}: {}, No.{}, {} Bytes",
-                            name,
-                            inode.r#type,
-                            entry.inum,
-                            inode.size,
-                            width = DIRSIZ
-                        );
-                    }
-                }
-            }
+    if raw_inode_type(img, inode_num, sblock) != InodeType::T_DIR as i16 {
+        println!("{}: {}", path, describe_inode(img, inode_num, sblock));
+        return;
+    }
+    for i in 0..NDIRECT {
+        if inode.addrs[i] == 0 {
+            break;
         }
-        InodeType::T_FILE | InodeType::T_DEV => {
+        for entry in extract_dirents_pointer_im(&img, inode.addrs[i] as usize).into_iter() {
+            let name = from_utf8(&entry.name).unwrap().trim_matches(char::from(0));
+            if name.is_empty() {
+                continue;
+            }
             println!(
-                "{}: {}, No.{}, {} Bytes",
-                path, inode.r#type, inode_num, inode.size
+                "{:<width$}: {}",
+                name,
+                describe_inode(img, entry.inum.into(), sblock),
+                width = DIRSIZ
             );
         }
-        InodeType::ZERO => {
-            panic!("get: type field is not set.");
+    }
+    if inode.addrs[NDIRECT] != 0 {
+        // indirect reference block
+        for i in extract_indirect_reference_block_pointer_im(&img, inode.addrs[NDIRECT] as usize)
+            .into_iter()
+        {
+            if *i == 0u32 {
+                break;
+            }
+            for entry in extract_dirents_pointer_im(&img, (*i) as usize).into_iter() {
+                let name = from_utf8(&entry.name).unwrap().trim_matches(char::from(0));
+                if name.is_empty() {
+                    continue;
+                }
+                println!(
+                    "{:<width$}: {}",
+                    name,
+                    describe_inode(img, entry.inum.into(), sblock),
+                    width = DIRSIZ
+                );
+            }
         }
     }
 }
 
-pub fn get(img: &MmapMut, src: &str, dst: &str, sblock: &superblock) {
-    use std::io::prelude::*;
+// "type, No.N, size" of the inode. type is read raw, so that entries of broken image
+// (e.g. made by `corrupt free-inode`) are shown as they are
+fn describe_inode(img: &[u8], inode_num: usize, sblock: &superblock) -> String {
+    if inode_num >= sblock.ninodes as usize {
+        return format!("out of range, No.{}", inode_num);
+    }
+    let r#type = raw_inode_type(img, inode_num, sblock);
+    if r#type == InodeType::ZERO as i16 {
+        return format!("free inode, No.{}", inode_num);
+    }
+    if r#type < InodeType::T_DIR as i16 || r#type > InodeType::T_DEV as i16 {
+        return format!("invalid type {}, No.{}", r#type, inode_num);
+    }
+    let inode = extract_inode_pointer_im(img, inode_num, sblock);
+    format!(
+        "{}, No.{}, {}",
+        inode.r#type,
+        inode_num,
+        size_or_device(inode)
+    )
+}
+
+// device numbers for device file, and size in bytes for others
+fn size_or_device(inode: &dinode) -> String {
+    match inode.r#type {
+        InodeType::T_DEV => format!("major {}, minor {}", inode.major, inode.minor),
+        _ => format!("{} Bytes", inode.size),
+    }
+}
 
+pub fn get(img: &MmapMut, src: &str, dst: &str, recursive: bool, sblock: &superblock) {
     let inode = explore_path(&img, src, &sblock);
     if inode.is_err() {
         eprintln!("get: {}", inode.unwrap_err());
         exit(1);
     }
-    let (inode, _): (&dinode, usize) = inode.unwrap();
+    let (inode, inode_num): (&dinode, usize) = inode.unwrap();
     match inode.r#type {
         InodeType::T_DIR => {
-            eprintln!("get: {} is a directory.", src);
-            exit(1);
+            if !recursive {
+                eprintln!("get: {} is a directory.", src);
+                exit(1);
+            }
         }
         InodeType::T_DEV => {
             eprintln!("get: {} is a device file.", src);
@@ -98,191 +117,333 @@ pub fn get(img: &MmapMut, src: &str, dst: &str, sblock: &superblock) {
             panic!("get: type field is not set.");
         }
     }
-    let dst_file = crate::file::open_new_file(dst);
-    if dst_file.is_err() {
-        eprintln!("get: cannot open file: {}", dst);
-    }
-    let mut dst_file = dst_file.unwrap();
-    let mut written_size: usize = 0;
-    let file_size: usize = inode.size as usize;
-    for i in 0..NDIRECT {
-        if inode.addrs[i] == 0 {
-            break;
-        }
-        match dst_file.write(
-            &img[(inode.addrs[i] as usize) * BLOCK_SIZE
-                ..(inode.addrs[i] as usize) * BLOCK_SIZE
-                    + BLOCK_SIZE.min(file_size - written_size)],
-        ) {
-            Ok(s) => written_size += s,
-            Err(e) => {
-                eprintln!("get: {}", e);
-                exit(1);
-            }
+    if inode.r#type == InodeType::T_FILE {
+        if let Err(e) = get_file(img, inode, Path::new(dst)) {
+            eprintln!("get: {}", e);
+            exit(1);
         }
+        return;
     }
-    if inode.addrs[NDIRECT] != 0 {
-        // indirect reference block
-        for i in extract_indirect_reference_block_pointer_im(&img, inode.addrs[NDIRECT] as usize)
-            .into_iter()
-        {
-            if *i == 0u32 {
-                break;
-            }
-            match dst_file.write(
-                &img[(*i as usize) * BLOCK_SIZE
-                    ..(*i as usize) * BLOCK_SIZE + BLOCK_SIZE.min(file_size - written_size)],
-            ) {
-                Ok(s) => written_size += s,
-                Err(e) => {
-                    eprintln!("get: {}", e);
-                    exit(1);
-                }
-            }
-        }
+
+    // extracting into existing directory keeps the directory name
+    let dst = dst.trim_end_matches('/');
+    let dst = if Path::new(dst).is_dir() && !split_path(src).1.is_empty() {
+        format!("{}/{}", dst, split_path(src).1)
+    } else {
+        dst.to_string()
+    };
+    let mut devices: Vec<String> = Vec::new();
+    if let Err(e) = get_tree(img, inode_num, Path::new(&dst), "", &mut devices, sblock) {
+        eprintln!("get: {}", e);
+        exit(1);
     }
-    if written_size != (inode.size as usize) {
+    if !devices.is_empty() {
+        // host cannot make device files, so they are recorded in sidecar file
+        let sidecar = format!("{}.devices", dst);
+        let mut contents = String::from("# major minor path\n");
+        for line in devices.iter() {
+            contents.push_str(line);
+            contents.push('\n');
+        }
+        if let Err(e) = std::fs::write(&sidecar, contents) {
+            eprintln!("get: {}: {}", sidecar, e);
+            exit(1);
+        }
         eprintln!(
-            "get: written size does not match. expected: {}, actual: {}",
-            inode.size, written_size
+            "get: {} device files are recorded in {}",
+            devices.len(),
+            sidecar
         );
     }
 }
 
-// for now, even if block becomes empty by deleting this file, not release the block.
-pub fn rm(img: &mut MmapMut, path: &str, sblock: &superblock) {
-    let mut dirent_block_number = 0;
-    let mut dirent_offset = 0;
-    let mut inode_num = ROOT_INODE;
+fn get_file(img: &MmapMut, inode: &dinode, dst: &Path) -> Result<(), String> {
+    use std::io::prelude::*;
 
-    let mut data_block_nums: [u32; NDIRECT + 1] = [0; NDIRECT + 1];
-    let mut del_flag = false; // delete file or not
+    let mut dst_file = crate::file::open_new_file(&dst.to_string_lossy())
+        .map_err(|_| format!("cannot open file: {}", dst.display()))?;
+    let data = read_data(img, inode);
+    dst_file
+        .write_all(&data)
+        .map_err(|e| format!("{}: {}", dst.display(), e))?;
+    if data.len() != (inode.size as usize) {
+        eprintln!(
+            "get: written size does not match. expected: {}, actual: {}",
+            inode.size,
+            data.len()
+        );
+    }
+    Ok(())
+}
 
-    let mut current_inode: &dinode = extract_inode_pointer_im(&img, inode_num, &sblock);
-    if path != "/" {
-        'directory: for file_name in path.split("/").skip(1) {
-            for i in 0..NDIRECT {
-                if current_inode.addrs[i] == 0 {
-                    break;
-                }
-                for (j, entry) in extract_dirents_pointer_im(&img, current_inode.addrs[i] as usize)
-                    .into_iter()
-                    .enumerate()
-                {
-                    if file_name == from_utf8(&entry.name).unwrap().trim_matches(char::from(0)) {
-                        dirent_block_number = current_inode.addrs[i];
-                        current_inode = extract_inode_pointer_im(&img, entry.inum.into(), &sblock);
-                        inode_num = entry.inum as usize;
-                        dirent_offset = j;
-                        continue 'directory;
-                    }
-                }
+// recreate the directory tree on the host. device files are collected into devices
+// as "major minor path" (path is relative to the top of the tree).
+fn get_tree(
+    img: &MmapMut,
+    dir_inode_num: usize,
+    dst: &Path,
+    relative_path: &str,
+    devices: &mut Vec<String>,
+    sblock: &superblock,
+) -> Result<(), String> {
+    if !dst.is_dir() {
+        std::fs::create_dir(dst).map_err(|e| format!("{}: {}", dst.display(), e))?;
+    }
+    let dir_inode = extract_inode_pointer_im(img, dir_inode_num, sblock);
+    for block_num in inode_block_numbers(img, dir_inode) {
+        for entry in extract_dirents_pointer_im(img, block_num).iter() {
+            let name = dirent_name(entry);
+            if entry.inum == 0 || name == "." || name == ".." {
+                continue;
             }
-            if current_inode.addrs[NDIRECT] != 0 {
-                // indirect reference block
-                for i in extract_indirect_reference_block_pointer_im(
-                    &img,
-                    current_inode.addrs[NDIRECT] as usize,
-                )
-                .into_iter()
-                {
-                    if *i == 0u32 {
-                        continue;
-                    }
-                    for (j, entry) in extract_dirents_pointer_im(&img, (*i) as usize)
-                        .into_iter()
-                        .enumerate()
-                    {
-                        if file_name == from_utf8(&entry.name).unwrap().trim_matches(char::from(0))
-                        {
-                            current_inode =
-                                extract_inode_pointer_im(&img, entry.inum.into(), &sblock);
-                            inode_num = entry.inum as usize;
-                            dirent_block_number = *i;
-                            dirent_offset = j;
-                            continue 'directory;
-                        }
-                    }
+            let child = dst.join(name);
+            let child_relative_path = if relative_path.is_empty() {
+                name.to_string()
+            } else {
+                format!("{}/{}", relative_path, name)
+            };
+            let inode = extract_inode_pointer_im(img, entry.inum as usize, sblock);
+            match inode.r#type {
+                InodeType::T_DIR => get_tree(
+                    img,
+                    entry.inum as usize,
+                    &child,
+                    &child_relative_path,
+                    devices,
+                    sblock,
+                )?,
+                InodeType::T_FILE => get_file(img, inode, &child)?,
+                InodeType::T_DEV => devices.push(format!(
+                    "{} {} {}",
+                    inode.major, inode.minor, child_relative_path
+                )),
+                InodeType::ZERO => {
+                    return Err(format!("{}: type field is not set.", child.display()));
                 }
             }
-            // coming here means file does not exist.
-            eprintln!("{}: no such file or directory", path);
-            exit(1);
         }
-    } else {
+    }
+    Ok(())
+}
+
+pub fn rm(img: &mut MmapMut, path: &str, recursive: bool, sblock: &superblock) {
+    if path == "/" {
         eprintln!("rm: cannot remove /.");
         exit(1);
     }
+    let (parent_inode_num, inode_num, dirent_block_number, dirent_offset) =
+        match lookup_path_dirent(img, path, sblock) {
+            Ok(t) => t,
+            Err(e) => {
+                eprintln!("rm: {}", e);
+                exit(1);
+            }
+        };
 
-    match current_inode.r#type {
+    match extract_inode_pointer_im(img, inode_num, sblock).r#type {
         InodeType::T_DIR => {
-            eprintln!("rm: {} is a directory.", path);
+            if !recursive {
+                eprintln!("rm: {} is a directory.", path);
+                exit(1);
+            }
+            remove_tree(img, inode_num, sblock);
+            // ".." of removed directory referred to parent
+            extract_inode_pointer(img, parent_inode_num, sblock).nlink -= 1;
+        }
+        InodeType::T_FILE | InodeType::T_DEV => unlink_inode(img, inode_num, sblock),
+        InodeType::ZERO => {
+            panic!("rm: type field is not set.");
+        }
+    }
+
+    // finally, delete directory entry, and release blocks which become empty
+    remove_dirent(
+        img,
+        parent_inode_num,
+        dirent_block_number,
+        dirent_offset,
+        sblock,
+    );
+    if let Err(e) = compact_directory(img, parent_inode_num, false, sblock) {
+        eprintln!("rm: {}", e);
+        exit(1);
+    }
+}
+
+pub fn rmdir(img: &mut MmapMut, path: &str, sblock: &superblock) {
+    if path == "/" {
+        eprintln!("rmdir: cannot remove /.");
+        exit(1);
+    }
+    let (parent_inode_num, inode_num, dirent_block_number, dirent_offset) =
+        match lookup_path_dirent(img, path, sblock) {
+            Ok(t) => t,
+            Err(e) => {
+                eprintln!("rmdir: {}", e);
+                exit(1);
+            }
+        };
+    let inode = extract_inode_pointer_im(img, inode_num, sblock);
+    if inode.r#type != InodeType::T_DIR {
+        eprintln!("rmdir: {}: not a directory", path);
+        exit(1);
+    }
+    for block_num in inode_block_numbers(img, inode) {
+        for entry in extract_dirents_pointer_im(img, block_num).iter() {
+            let name = dirent_name(entry);
+            if entry.inum != 0 && name != "." && name != ".." {
+                eprintln!("rmdir: {}: directory not empty", path);
+                exit(1);
+            }
+        }
+    }
+
+    free_data_blocks(img, inode, sblock);
+    clear_inode(img, inode_num, sblock);
+    // ".." of removed directory referred to parent
+    extract_inode_pointer(img, parent_inode_num, sblock).nlink -= 1;
+    remove_dirent(
+        img,
+        parent_inode_num,
+        dirent_block_number,
+        dirent_offset,
+        sblock,
+    );
+    if let Err(e) = compact_directory(img, parent_inode_num, false, sblock) {
+        eprintln!("rmdir: {}", e);
+        exit(1);
+    }
+}
+
+pub fn compact(img: &mut MmapMut, path: &str, sorted: bool, sblock: &superblock) {
+    let inode_num = match explore_path(img, path, sblock) {
+        Ok((inode, _)) if inode.r#type != InodeType::T_DIR => {
+            eprintln!("compact: {}: not a directory", path);
             exit(1);
         }
-        InodeType::T_DEV => {
-            eprintln!("rm: {} is a device file.", path);
+        Ok((_, n)) => n,
+        Err(e) => {
+            eprintln!("compact: {}", e);
             exit(1);
         }
-        InodeType::T_FILE => {}
-        InodeType::ZERO => {
-            panic!("get: type field is not set.");
+    };
+    if let Err(e) = compact_directory(img, inode_num, sorted, sblock) {
+        eprintln!("compact: {}", e);
+        exit(1);
+    }
+}
+
+// check that path can be created, and return (parent inode number, new file name)
+fn prepare_new_entry<'a>(
+    img: &MmapMut,
+    path: &'a str,
+    sblock: &superblock,
+) -> Result<(usize, &'a str), String> {
+    let (parent, name) = split_path(path);
+    let (parent_inode, parent_inode_num) = explore_path(img, parent, sblock)?;
+    if parent_inode.r#type != InodeType::T_DIR {
+        return Err(format!("{}: not a directory", parent));
+    }
+    if lookup_dirent(img, parent_inode, name).is_some() {
+        // file already exists(for now, overriding is not compatible)
+        return Err(format!("{}: already exists.", path));
+    }
+    make_dirent(0, name)?;
+    Ok((parent_inode_num, name))
+}
+
+// when destination is an existing directory, the file goes into it keeping its name
+fn resolve_destination(img: &MmapMut, name: &str, dst: &str, sblock: &superblock) -> String {
+    match explore_path(img, dst, sblock) {
+        Ok((inode, _)) if inode.r#type == InodeType::T_DIR => {
+            format!("{}/{}", dst.trim_end_matches('/'), name)
+        }
+        _ => dst.to_string(),
+    }
+}
+
+// check whether the directory `ancestor` contains the directory `dir` (or is `dir` itself)
+fn is_ancestor(img: &MmapMut, ancestor: usize, dir: usize, sblock: &superblock) -> bool {
+    let mut current = dir;
+    loop {
+        if current == ancestor {
+            return true;
+        }
+        if current == ROOT_INODE {
+            return false;
+        }
+        match lookup_dirent(img, extract_inode_pointer_im(img, current, sblock), "..") {
+            Some((n, _, _)) if n != current => current = n,
+            _ => return false,
         }
     }
+}
+
+// find the directory entry of path, and return
+// (parent inode number, inode number, dirent block number, dirent offset)
+fn lookup_path_dirent(
+    img: &MmapMut,
+    path: &str,
+    sblock: &superblock,
+) -> Result<(usize, usize, usize, usize), String> {
+    let (parent, name) = split_path(path);
+    let (parent_inode, parent_inode_num) = explore_path(img, parent, sblock)?;
+    if parent_inode.r#type != InodeType::T_DIR {
+        return Err(format!("{}: not a directory", parent));
+    }
+    if name == "." || name == ".." {
+        return Err(format!("{}: cannot remove \".\" or \"..\"", path));
+    }
+    match lookup_dirent(img, parent_inode, name) {
+        Some((inode_num, block_num, offset)) => {
+            Ok((parent_inode_num, inode_num, block_num, offset))
+        }
+        None => Err(format!("{}: no such file or directory", path)),
+    }
+}
 
-    // update inode
-    let current_inode: &mut dinode = extract_inode_pointer(&img, inode_num, &sblock);
-    if current_inode.nlink != 1 {
+// drop one link of the inode, and release it when no link remains
+fn unlink_inode(img: &MmapMut, inode_num: usize, sblock: &superblock) {
+    let inode: &mut dinode = extract_inode_pointer(img, inode_num, sblock);
+    if inode.nlink > 1 {
         // only decrement its nlink
-        (*current_inode).nlink -= 1;
+        inode.nlink -= 1;
     } else {
-        del_flag = true;
-        data_block_nums = current_inode.addrs.clone();
-
-        *current_inode = dinode {
-            r#type: InodeType::ZERO,
-            major: 0,
-            minor: 0,
-            nlink: 0,
-            size: 0,
-            addrs: [0; NDIRECT + 1],
-        };
+        free_data_blocks(img, inode, sblock);
+        clear_inode(img, inode_num, sblock);
     }
-    // delete related data block.
-    if del_flag {
-        for i in data_block_nums[..NDIRECT].iter() {
-            if *i == 0 {
-                break;
+}
+
+// release the directory and everything under it (depth-first)
+fn remove_tree(img: &MmapMut, dir_inode_num: usize, sblock: &superblock) {
+    let dir_inode = extract_inode_pointer_im(img, dir_inode_num, sblock);
+    for block_num in inode_block_numbers(img, dir_inode) {
+        for entry in extract_dirents_pointer_im(img, block_num).iter() {
+            let name = dirent_name(entry);
+            if entry.inum == 0 || name == "." || name == ".." {
+                continue;
             }
-            let block: &mut [u8; BLOCK_SIZE] = extract_block_pointer(&img, *i as usize);
-            *block = [0u8; BLOCK_SIZE];
-        }
-        // indirect reference block
-        if data_block_nums[NDIRECT] != 0 {
-            for i in
-                extract_indirect_reference_block_pointer_im(&img, data_block_nums[NDIRECT] as usize)
-                    .iter()
-            {
-                let block: &mut [u8; BLOCK_SIZE] = extract_block_pointer(&img, *i as usize);
-                *block = [0u8; BLOCK_SIZE];
+            let inode_num = entry.inum as usize;
+            match extract_inode_pointer_im(img, inode_num, sblock).r#type {
+                InodeType::T_DIR => remove_tree(img, inode_num, sblock),
+                InodeType::T_FILE | InodeType::T_DEV => unlink_inode(img, inode_num, sblock),
+                InodeType::ZERO => {}
             }
-            let block: &mut [u8; BLOCK_SIZE] =
-                extract_block_pointer(&img, data_block_nums[NDIRECT] as usize);
-            *block = [0u8; BLOCK_SIZE];
         }
     }
-
-    // finally, delete directory entry
-    let dirent: &mut dirent =
-        extract_dirent_pointer(&img, dirent_block_number as usize, dirent_offset);
-    *dirent = dirent {
-        inum: 0,
-        name: [0u8; DIRSIZ],
-    };
+    free_data_blocks(img, dir_inode, sblock);
+    clear_inode(img, dir_inode_num, sblock);
 }
 
-pub fn put(img: &mut MmapMut, src: &str, dst: &str, sblock: &superblock) {
-    use std::fs::{metadata, File};
-    use std::io::prelude::*;
+pub fn put(
+    img: &mut MmapMut,
+    src: &str,
+    dst: &str,
+    recursive: bool,
+    force: bool,
+    sblock: &superblock,
+) {
+    use std::fs::metadata;
 
     let met = match metadata(src) {
         Ok(m) => m,
@@ -291,74 +452,187 @@ pub fn put(img: &mut MmapMut, src: &str, dst: &str, sblock: &superblock) {
             exit(1);
         }
     };
+    if met.file_type().is_dir() {
+        if !recursive {
+            eprintln!("put: {} is a directory.", src);
+            exit(1);
+        }
+        let name = match std::fs::canonicalize(src) {
+            Ok(p) => p
+                .file_name()
+                .map(|n| n.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            Err(e) => {
+                eprintln!("put: {}", e);
+                exit(1);
+            }
+        };
+        let dst = resolve_destination(img, &name, dst, sblock);
+        let mut skipped: usize = 0;
+        if let Err(e) = put_tree(img, Path::new(src), &dst, force, &mut skipped, sblock) {
+            eprintln!("put: {}", e);
+            exit(1);
+        }
+        if skipped != 0 {
+            eprintln!("put: {} entries are skipped.", skipped);
+            exit(1);
+        }
+        return;
+    }
     if !met.file_type().is_file() {
         eprintln!("put: currently cannot put non-regular file.");
         exit(1);
     }
-    // whole allocatable block size
-    if met.len()
-        > (BLOCK_SIZE * NDIRECT + BLOCK_SIZE * (BLOCK_SIZE / std::mem::size_of::<u32>())) as u64
-    {
-        eprintln!(
-            "put: {} is too large (must be {} bytes or smaller)",
-            src,
-            BLOCK_SIZE * NDIRECT + BLOCK_SIZE * (BLOCK_SIZE / std::mem::size_of::<u32>())
-        );
+
+    if dst == "/" {
+        eprintln!("put: cannot override /.");
         exit(1);
     }
 
-    // for parallelism, mutual exclusion (by locking img) may be needed.
-    let candidate_inode_number = search_for_available_inode(&img, &sblock);
-    if candidate_inode_number.is_err() {
-        eprintln!("put: {}", candidate_inode_number.unwrap_err());
+    if let Err(e) = put_file(img, Path::new(src), dst, force, sblock) {
+        eprintln!("put: {}", e);
         exit(1);
     }
-    let candidate_inode_number = candidate_inode_number.unwrap();
+}
+
+// put the host file into dst. if force, existing dst is overwritten in place.
+fn put_file(
+    img: &MmapMut,
+    src: &Path,
+    dst: &str,
+    force: bool,
+    sblock: &superblock,
+) -> Result<(), String> {
+    use std::fs::File;
+    use std::io::prelude::*;
+
+    let mut data: Vec<u8> = Vec::new();
+    if let Err(e) = File::open(src).and_then(|mut f| f.read_to_end(&mut data)) {
+        return Err(format!("{}: {}", src.display(), e));
+    }
+    // whole allocatable block size
+    if data.len() > BLOCK_SIZE * NDIRECT + BLOCK_SIZE * U32_PER_BLOCK {
+        return Err(format!(
+            "{} is too large (must be {} bytes or smaller)",
+            src.display(),
+            BLOCK_SIZE * NDIRECT + BLOCK_SIZE * U32_PER_BLOCK
+        ));
+    }
+    if force {
+        if let Ok((inode, inode_num)) = explore_path(img, dst, sblock) {
+            if inode.r#type != InodeType::T_FILE {
+                return Err(format!("{}: cannot overwrite non-regular file", dst));
+            }
+            return overwrite_file(img, inode_num, &data, sblock);
+        }
+    }
+    create_file(img, dst, InodeType::T_FILE, &data, sblock)?;
+    Ok(())
+}
+
+// replace contents of the file keeping its inode, reusing its blocks
+// and allocating or releasing only the difference
+fn overwrite_file(
+    img: &MmapMut,
+    inode_num: usize,
+    data: &[u8],
+    sblock: &superblock,
+) -> Result<(), String> {
+    // on failure, write_data leaves the file unchanged
+    write_data(img, inode_num, 0, data, sblock)?;
+    resize_inode(img, inode_num, data.len(), false, sblock)
+}
+
+// mirror the host directory tree into dst, making directories as needed.
+// entries whose name does not fit in a dirent are reported and skipped.
+fn put_tree(
+    img: &MmapMut,
+    src: &Path,
+    dst: &str,
+    force: bool,
+    skipped: &mut usize,
+    sblock: &superblock,
+) -> Result<(), String> {
+    match explore_path(img, dst, sblock) {
+        Ok((inode, _)) if inode.r#type == InodeType::T_DIR => {}
+        Ok(_) => return Err(format!("{}: not a directory", dst)),
+        Err(_) => {
+            make_directory(img, dst, sblock)?;
+        }
+    }
+
+    let mut entries: Vec<std::fs::DirEntry> = std::fs::read_dir(src)
+        .and_then(|d| d.collect())
+        .map_err(|e| format!("{}: {}", src.display(), e))?;
+    entries.sort_by_key(|e| e.file_name());
+    for entry in entries {
+        let path = entry.path();
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.len() > DIRSIZ {
+            eprintln!(
+                "put: {}: file name too long (must be {} bytes or shorter), skipped",
+                path.display(),
+                DIRSIZ
+            );
+            *skipped += 1;
+            continue;
+        }
+        let met = std::fs::metadata(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let child = format!("{}/{}", dst.trim_end_matches('/'), name);
+        if met.file_type().is_dir() {
+            put_tree(img, &path, &child, force, skipped, sblock)?;
+        } else if met.file_type().is_file() {
+            put_file(img, &path, &child, force, sblock)?;
+        } else {
+            eprintln!("put: {}: not a regular file, skipped", path.display());
+            *skipped += 1;
+        }
+    }
+    Ok(())
+}
+
+// allocate new inode holding the data, and link it as path. return its inode number.
+fn create_file(
+    img: &MmapMut,
+    path: &str,
+    r#type: InodeType,
+    data: &[u8],
+    sblock: &superblock,
+) -> Result<usize, String> {
+    // find out parent directory first, so that nothing is allocated on failure
+    let (parent_inode_num, file_name) = prepare_new_entry(img, path, sblock)?;
+
+    // for parallelism, mutual exclusion (by locking img) may be needed.
+    let candidate_inode_number = search_for_available_inode(img, sblock)?;
+    let new_dirent = make_dirent(candidate_inode_number, file_name)?;
 
     let mut inode = dinode {
-        r#type: InodeType::T_FILE,
+        r#type,
         major: 0,
         minor: 0,
         nlink: 1,
-        size: met.len() as u32,
-        addrs: [0; 13],
-    };
-    let mut src_file = match File::open(src) {
-        Ok(f) => f,
-        Err(e) => {
-            eprintln!("put: {}", e);
-            exit(1);
-        }
+        size: data.len() as u32,
+        addrs: [0; NDIRECT + 1],
     };
-    let mut buf: [u8; BLOCK_SIZE] = [0; BLOCK_SIZE];
     let mut block_nums: Vec<usize> = Vec::new();
-    while let Ok(_) = src_file.read_exact(&mut buf) {
-        let block_num = match search_for_available_dblock(&img, &sblock) {
-            Ok(i) => i,
+    let mut chunks: Vec<&[u8]> = data.chunks(BLOCK_SIZE).collect();
+    if NDIRECT < chunks.len() {
+        chunks.push(&[]); // for indirect reference block
+    }
+    for chunk in chunks {
+        let block_num = match search_for_available_dblock(img, sblock) {
+            Ok(n) => n,
             Err(e) => {
-                eprintln!("put: {}", e);
-                exit(1);
+                // release what has been allocated so far
+                for block_num in block_nums {
+                    free_block(img, block_num, sblock);
+                }
+                return Err(e);
             }
         };
-        let block: &mut [u8; BLOCK_SIZE] = extract_block_pointer(&img, block_num);
-        *block = buf;
-
+        extract_block_pointer(img, block_num)[..chunk.len()].copy_from_slice(chunk);
         block_nums.push(block_num);
-        buf = [0; BLOCK_SIZE];
     }
-    // here buf has rest of file
-    let block_num = match search_for_available_dblock(&img, &sblock) {
-        Ok(i) => i,
-        Err(e) => {
-            eprintln!("put: {}", e);
-            exit(1);
-        }
-    };
-
-    let block: &mut [u8; BLOCK_SIZE] = extract_block_pointer(&img, block_num);
-    *block = buf;
-
-    block_nums.push(block_num);
 
     for (i, num) in block_nums[..NDIRECT.min(block_nums.len())]
         .iter()
@@ -368,238 +642,979 @@ pub fn put(img: &mut MmapMut, src: &str, dst: &str, sblock: &superblock) {
     }
 
     if NDIRECT < block_nums.len() {
-        // allocate indirect reference block
-        let block_num = match search_for_available_dblock(&img, &sblock) {
-            Ok(i) => i,
-            Err(e) => {
-                eprintln!("put: {}", e);
-                exit(1);
-            }
-        };
+        // indirect reference block is allocated last
+        let block_num = block_nums.pop().unwrap();
         inode.addrs[NDIRECT] = block_num as u32;
         let ref_block: &mut [u32; U32_PER_BLOCK] =
-            extract_indirect_reference_block_pointer(&img, block_num);
+            extract_indirect_reference_block_pointer(img, block_num);
 
         for (i, num) in block_nums[NDIRECT..].iter().enumerate() {
-            (*ref_block)[i] = *num as u32;
+            ref_block[i] = *num as u32;
         }
     }
 
     // make new inode
-    let new_inode: &mut dinode = extract_inode_pointer(&img, candidate_inode_number, &sblock);
+    let new_inode: &mut dinode = extract_inode_pointer(img, candidate_inode_number, sblock);
     *new_inode = inode;
 
-    let dst_split: Vec<&str> = dst.split('/').collect();
-    let dst_file_name = dst_split[dst_split.len() - 1];
-    let dst_parent_name = dst_split[dst_split.len() - 2];
-
-    let mut new_name: [u8; DIRSIZ] = [0u8; DIRSIZ];
-    for (i, c) in dst_file_name.as_bytes().iter().enumerate() {
-        new_name[i] = *c;
+    // finally, link it into parent directory
+    if let Err(e) = add_dirent(img, parent_inode_num, new_dirent, sblock) {
+        free_data_blocks(img, &inode, sblock);
+        clear_inode(img, candidate_inode_number, sblock);
+        return Err(e);
     }
+    Ok(candidate_inode_number)
+}
 
-    // make dirent
-    let new_dirent: dirent = dirent {
-        inum: candidate_inode_number as u16,
-        name: new_name,
-    };
-
-    // search for empty entry
-    if dst != "/" {
-        let mut current_inode: &dinode = extract_inode_pointer_im(&img, ROOT_INODE, &sblock);
-        let mut current_inode_num: usize = ROOT_INODE;
-
-        // first, find out parent directory
-        'directory: for file_name in dst_split.iter().skip(1) {
-            for j in 0..NDIRECT {
-                if current_inode.addrs[j] == 0 {
-                    break;
-                }
-                for entry in
-                    extract_dirents_pointer_im(&img, current_inode.addrs[j] as usize).into_iter()
-                {
-                    let name = from_utf8(&entry.name).unwrap().trim_matches(char::from(0));
-                    if name == dst_parent_name {
-                        break 'directory;
-                    }
-                    if name == *file_name {
-                        current_inode = extract_inode_pointer(&img, entry.inum.into(), &sblock);
-                        current_inode_num = entry.inum.into();
-                        continue 'directory;
-                    }
-                }
-            }
-            if current_inode.addrs[NDIRECT] != 0 {
-                // indirect reference block
-                for i in extract_indirect_reference_block_pointer_im(
-                    &img,
-                    current_inode.addrs[NDIRECT] as usize,
-                )
-                .into_iter()
-                {
-                    if *i == 0u32 {
-                        break;
-                    }
-                    for entry in extract_dirents_pointer_im(&img, (*i) as usize).into_iter() {
-                        let name = from_utf8(&entry.name).unwrap().trim_matches(char::from(0));
-                        if name == dst_parent_name {
-                            break 'directory;
-                        }
-                        if name == *file_name {
-                            current_inode = extract_inode_pointer(&img, entry.inum.into(), &sblock);
-                            current_inode_num = entry.inum.into();
-                            continue 'directory;
-                        }
-                    }
-                }
-            }
-            // coming here means parent directory does not exist.
-            eprintln!("put: {}: no such file or directory", file_name);
+pub fn mkdir(img: &mut MmapMut, path: &str, parents: bool, sblock: &superblock) {
+    if path == "/" {
+        if parents {
+            return;
+        }
+        eprintln!("mkdir: /: already exists.");
+        exit(1);
+    }
+    if !parents {
+        if let Err(e) = make_directory(img, path, sblock) {
+            eprintln!("mkdir: {}", e);
             exit(1);
         }
-        // here current_inode is parent's inode
-
-        let mut dirent_block_number: usize = 0;
-        let mut dirent_block_offset: usize = 0;
-
-        // search for empty dirent space and check whether file with same name exists
-        for i in 0..NDIRECT {
-            if current_inode.addrs[i] == 0 {
-                continue;
+        return;
+    }
+    // create each missing ancestor, like `mkdir -p`
+    let mut ancestor = String::new();
+    for name in path.split('/').filter(|s| !s.is_empty()) {
+        ancestor.push('/');
+        ancestor.push_str(name);
+        let ancestor = ancestor.as_str();
+        match explore_path(img, ancestor, sblock) {
+            Ok((inode, _)) if inode.r#type == InodeType::T_DIR => continue,
+            Ok(_) => {
+                eprintln!("mkdir: {}: not a directory", ancestor);
+                exit(1);
             }
-            for (j, entry) in extract_dirents_pointer_im(&img, current_inode.addrs[i] as usize)
-                .into_iter()
-                .enumerate()
-            {
-                let name = from_utf8(&entry.name).unwrap().trim_matches(char::from(0));
-                if entry.inum == 0 && name.is_empty() {
-                    // can make dirent here
-                    dirent_block_number = current_inode.addrs[i] as usize;
-                    dirent_block_offset = j;
-                }
-                if name == dst_file_name {
-                    // file already exists(for now, overriding is not compatible)
-                    eprintln!("put: {}: already exists.", dst);
-                    exit(1);
-                }
+            Err(_) => {}
+        }
+        if let Err(e) = make_directory(img, ancestor, sblock) {
+            eprintln!("mkdir: {}", e);
+            exit(1);
+        }
+    }
+}
+
+// make an empty directory, and return its inode number
+fn make_directory(img: &MmapMut, path: &str, sblock: &superblock) -> Result<usize, String> {
+    let (parent_inode_num, name) = prepare_new_entry(img, path, sblock)?;
+
+    let inode_num = search_for_available_inode(img, sblock)?;
+    let new_dirent = make_dirent(inode_num, name)?;
+    let block_num = search_for_available_dblock(img, sblock)?;
+
+    // "." and ".." are the first two entries
+    let dblock: &mut [dirent; BLOCK_SIZE / DIRENT_SIZE] = extract_dirents_pointer(img, block_num);
+    for entry in dblock.iter_mut() {
+        *entry = dirent {
+            inum: 0,
+            name: [0u8; DIRSIZ],
+        };
+    }
+    (*dblock)[0] = make_dirent(inode_num, ".")?;
+    (*dblock)[1] = make_dirent(parent_inode_num, "..")?;
+
+    let mut addrs = [0; NDIRECT + 1];
+    addrs[0] = block_num as u32;
+    let new_inode: &mut dinode = extract_inode_pointer(img, inode_num, sblock);
+    *new_inode = dinode {
+        r#type: InodeType::T_DIR,
+        major: 0,
+        minor: 0,
+        nlink: 1,
+        size: (2 * DIRENT_SIZE) as u32,
+        addrs,
+    };
+
+    if let Err(e) = add_dirent(img, parent_inode_num, new_dirent, sblock) {
+        // release what has been allocated above
+        free_block(img, block_num, sblock);
+        clear_inode(img, inode_num, sblock);
+        return Err(e);
+    }
+    // ".." of new directory refers to parent
+    extract_inode_pointer(img, parent_inode_num, sblock).nlink += 1;
+    Ok(inode_num)
+}
+
+pub fn mknod(img: &mut MmapMut, path: &str, major: i16, minor: i16, sblock: &superblock) {
+    if let Err(e) = make_device(img, path, major, minor, sblock) {
+        eprintln!("mknod: {}", e);
+        exit(1);
+    }
+}
+
+// make a device file with the device numbers, and return its inode number
+fn make_device(
+    img: &MmapMut,
+    path: &str,
+    major: i16,
+    minor: i16,
+    sblock: &superblock,
+) -> Result<usize, String> {
+    let inode_num = create_file(img, path, InodeType::T_DEV, &[], sblock)?;
+    let inode: &mut dinode = extract_inode_pointer(img, inode_num, sblock);
+    inode.major = major;
+    inode.minor = minor;
+    Ok(inode_num)
+}
+
+pub fn touch(img: &mut MmapMut, path: &str, sblock: &superblock) {
+    if explore_path(img, path, sblock).is_ok() {
+        // xv6 has no timestamp to update
+        return;
+    }
+    if let Err(e) = create_file(img, path, InodeType::T_FILE, &[], sblock) {
+        eprintln!("touch: {}", e);
+        exit(1);
+    }
+}
+
+pub fn truncate(img: &mut MmapMut, path: &str, size: usize, sparse: bool, sblock: &superblock) {
+    let inode_num = match explore_path(img, path, sblock) {
+        Ok((inode, _)) if inode.r#type != InodeType::T_FILE => {
+            eprintln!("truncate: {}: not a regular file", path);
+            exit(1);
+        }
+        Ok((_, n)) => n,
+        Err(e) => {
+            eprintln!("truncate: {}", e);
+            exit(1);
+        }
+    };
+    if let Err(e) = resize_inode(img, inode_num, size, sparse, sblock) {
+        eprintln!("truncate: {}", e);
+        exit(1);
+    }
+}
+
+// write contents of host file src ("-" for stdin) into dst at offset (or at its end if append).
+// dst is made if it does not exist.
+pub fn write(
+    img: &mut MmapMut,
+    src: &str,
+    dst: &str,
+    offset: usize,
+    append: bool,
+    sblock: &superblock,
+) {
+    use std::fs::File;
+    use std::io::prelude::*;
+
+    let mut data: Vec<u8> = Vec::new();
+    let read = if src == "-" {
+        std::io::stdin().read_to_end(&mut data)
+    } else {
+        File::open(src).and_then(|mut f| f.read_to_end(&mut data))
+    };
+    if let Err(e) = read {
+        eprintln!("write: {}: {}", src, e);
+        exit(1);
+    }
+
+    let inode_num = match explore_path(img, dst, sblock) {
+        Ok((inode, _)) if inode.r#type != InodeType::T_FILE => {
+            eprintln!("write: {}: not a regular file", dst);
+            exit(1);
+        }
+        Ok((_, n)) => n,
+        Err(_) => match create_file(img, dst, InodeType::T_FILE, &[], sblock) {
+            Ok(n) => n,
+            Err(e) => {
+                eprintln!("write: {}", e);
+                exit(1);
             }
+        },
+    };
+    let offset = if append {
+        extract_inode_pointer_im(img, inode_num, sblock).size as usize
+    } else {
+        offset
+    };
+    if let Err(e) = write_data(img, inode_num, offset, &data, sblock) {
+        eprintln!("write: {}", e);
+        exit(1);
+    }
+}
+
+pub fn ln(img: &mut MmapMut, src: &str, dst: &str, sblock: &superblock) {
+    let inode_num = match explore_path(img, src, sblock) {
+        Ok((inode, _)) if inode.r#type == InodeType::T_DIR => {
+            eprintln!("ln: {} is a directory.", src);
+            exit(1);
         }
-        if current_inode.addrs[NDIRECT] != 0 {
-            for i in extract_indirect_reference_block_pointer_im(
-                &img,
-                current_inode.addrs[NDIRECT] as usize,
-            )
-            .into_iter()
-            {
-                if *i == 0 {
-                    break;
-                }
-                for (j, entry) in extract_dirents_pointer_im(&img, (*i) as usize)
-                    .into_iter()
-                    .enumerate()
-                {
-                    let name = from_utf8(&entry.name).unwrap().trim_matches(char::from(0));
-                    if entry.inum == 0 && name.is_empty() {
-                        // can make dirent here
-                        dirent_block_number = *i as usize;
-                        dirent_block_offset = j;
-                    }
-                    if name == dst_file_name {
-                        // file already exists(overriding is not compatible now)
-                        eprintln!("put: {}: already exists.", dst);
-                        exit(1);
-                    }
-                }
+        Ok((_, n)) => n,
+        Err(e) => {
+            eprintln!("ln: {}", e);
+            exit(1);
+        }
+    };
+    let (parent_inode_num, dst_file_name) = match prepare_new_entry(img, dst, sblock) {
+        Ok(t) => t,
+        Err(e) => {
+            eprintln!("ln: {}", e);
+            exit(1);
+        }
+    };
+    let new_dirent = make_dirent(inode_num, dst_file_name).unwrap();
+    if let Err(e) = add_dirent(img, parent_inode_num, new_dirent, sblock) {
+        eprintln!("ln: {}", e);
+        exit(1);
+    }
+    extract_inode_pointer(img, inode_num, sblock).nlink += 1;
+}
+
+pub fn mv(img: &mut MmapMut, src: &str, dst: &str, sblock: &superblock) {
+    if src == "/" {
+        eprintln!("mv: cannot move /.");
+        exit(1);
+    }
+    let (src_parent_inode_num, inode_num, dirent_block_number, dirent_offset) =
+        match lookup_path_dirent(img, src, sblock) {
+            Ok(t) => t,
+            Err(e) => {
+                eprintln!("mv: {}", e);
+                exit(1);
             }
+        };
+    let dst = resolve_destination(img, split_path(src).1, dst, sblock);
+    let (dst_parent_inode_num, dst_file_name) = match prepare_new_entry(img, &dst, sblock) {
+        Ok(t) => t,
+        Err(e) => {
+            eprintln!("mv: {}", e);
+            exit(1);
         }
+    };
+    let is_dir = extract_inode_pointer_im(img, inode_num, sblock).r#type == InodeType::T_DIR;
+    if is_dir && is_ancestor(img, inode_num, dst_parent_inode_num, sblock) {
+        eprintln!("mv: cannot move {} to a subdirectory of itself", src);
+        exit(1);
+    }
 
-        // cannot use existing blocks(because dirent_block_number is not changed since initialized)
-        if dirent_block_number == 0 {
-            for i in 0..NDIRECT {
-                if current_inode.addrs[i] == 0 {
-                    // allocate new block
-                    let block_num = search_for_available_dblock(&img, &sblock);
-                    if block_num.is_err() {
-                        eprintln!("put: {}", block_num.unwrap_err());
-                        exit(1);
-                    }
-                    let dblock: &mut [dirent; BLOCK_SIZE / DIRENT_SIZE] =
-                        extract_dirents_pointer(&img, block_num.unwrap());
+    let new_dirent = make_dirent(inode_num, dst_file_name).unwrap();
+    if dst_parent_inode_num == src_parent_inode_num {
+        // rename in place
+        *extract_dirent_pointer(img, dirent_block_number, dirent_offset) = new_dirent;
+        return;
+    }
+    if let Err(e) = add_dirent(img, dst_parent_inode_num, new_dirent, sblock) {
+        eprintln!("mv: {}", e);
+        exit(1);
+    }
+    remove_dirent(
+        img,
+        src_parent_inode_num,
+        dirent_block_number,
+        dirent_offset,
+        sblock,
+    );
 
-                    // initialize block
-                    (*dblock)[0] = new_dirent;
+    if is_dir {
+        // ".." now refers to new parent
+        let dir_inode = extract_inode_pointer_im(img, inode_num, sblock);
+        if let Some((_, block_num, offset)) = lookup_dirent(img, dir_inode, "..") {
+            extract_dirent_pointer(img, block_num, offset).inum = dst_parent_inode_num as u16;
+        }
+        extract_inode_pointer(img, src_parent_inode_num, sblock).nlink -= 1;
+        extract_inode_pointer(img, dst_parent_inode_num, sblock).nlink += 1;
+    }
+}
 
-                    for i in 1..BLOCK_SIZE / DIRENT_SIZE {
-                        (*dblock)[i] = dirent {
-                            inum: 0,
-                            name: [0u8; DIRSIZ],
-                        };
-                    }
-                    return;
+pub fn cp(img: &mut MmapMut, src: &str, dst: &str, recursive: bool, sblock: &superblock) {
+    let (src_inode, src_inode_num) = match explore_path(img, src, sblock) {
+        Ok(t) => t,
+        Err(e) => {
+            eprintln!("cp: {}", e);
+            exit(1);
+        }
+    };
+    let dst = resolve_destination(img, split_path(src).1, dst, sblock);
+    if src_inode.r#type == InodeType::T_DIR {
+        if !recursive {
+            eprintln!("cp: {} is a directory.", src);
+            exit(1);
+        }
+        match prepare_new_entry(img, &dst, sblock) {
+            Ok((parent_inode_num, _)) => {
+                if is_ancestor(img, src_inode_num, parent_inode_num, sblock) {
+                    eprintln!("cp: cannot copy {} into itself", src);
+                    exit(1);
                 }
             }
+            Err(e) => {
+                eprintln!("cp: {}", e);
+                exit(1);
+            }
+        }
+    }
+    if let Err(e) = copy_inode(img, src_inode_num, &dst, sblock) {
+        eprintln!("cp: {}", e);
+        exit(1);
+    }
+}
 
-            // direct reference block is full.
-            if current_inode.addrs[NDIRECT] == 0 {
-                let block_num = search_for_available_dblock(&img, &sblock);
-                if block_num.is_err() {
-                    eprintln!("put: {}", block_num.unwrap_err());
-                    exit(1);
-                }
-                let block_num = block_num.unwrap();
-                {
-                    // initialize indirect reference block
-                    let block: &mut [u32; U32_PER_BLOCK] =
-                        extract_indirect_reference_block_pointer(&img, block_num);
-                    *block = [0u32; U32_PER_BLOCK];
-                }
-                {
-                    let current_inode: &mut dinode =
-                        extract_inode_pointer(&img, current_inode_num, &sblock);
-                    (*current_inode).addrs[NDIRECT] = block_num as u32;
+// copy the inode (and everything under it, if directory) to path, with fresh inodes and blocks
+fn copy_inode(
+    img: &MmapMut,
+    inode_num: usize,
+    path: &str,
+    sblock: &superblock,
+) -> Result<(), String> {
+    let inode: dinode = *extract_inode_pointer_im(img, inode_num, sblock);
+    match inode.r#type {
+        InodeType::T_DIR => {
+            make_directory(img, path, sblock)?;
+            for block_num in inode_block_numbers(img, &inode) {
+                for entry in extract_dirents_pointer_im(img, block_num).iter() {
+                    let name = dirent_name(entry);
+                    if entry.inum == 0 || name == "." || name == ".." {
+                        continue;
+                    }
+                    copy_inode(
+                        img,
+                        entry.inum as usize,
+                        &format!("{}/{}", path, name),
+                        sblock,
+                    )?;
                 }
             }
+        }
+        InodeType::T_FILE => {
+            create_file(
+                img,
+                path,
+                InodeType::T_FILE,
+                &read_data(img, &inode),
+                sblock,
+            )?;
+        }
+        InodeType::T_DEV => {
+            make_device(img, path, inode.major, inode.minor, sblock)?;
+        }
+        InodeType::ZERO => {
+            return Err(format!("{}: type field is not set.", path));
+        }
+    }
+    Ok(())
+}
 
-            let ref_block = extract_indirect_reference_block_pointer(
-                &img,
-                current_inode.addrs[NDIRECT] as usize,
+// with files, this behaves like xv6's `mkfs fs.img file...`: every file is put into "/",
+// dropping its directory part and leading '_' (e.g. "user/_cat" becomes "/cat").
+pub fn mkfs(path: &str, size: u32, ninodes: u32, nlog: u32, files: &[&str]) {
+    let sblock = match sblock::new_superblock(size, ninodes, nlog) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("mkfs: {}", e);
+            exit(1);
+        }
+    };
+    if ninodes <= ROOT_INODE as u32 {
+        eprintln!("mkfs: ninodes must be greater than {}", ROOT_INODE);
+        exit(1);
+    }
+    if nlog < 2 {
+        eprintln!("mkfs: nlog must be 2 or greater (header block and at least one log block)");
+        exit(1);
+    }
+    if nlog as usize > U32_PER_BLOCK {
+        // header block holds count and block numbers of nlog - 1 blocks
+        eprintln!(
+            "mkfs: nlog must be {} or less (log header must fit in a block)",
+            U32_PER_BLOCK
+        );
+        exit(1);
+    }
+    // read all files first, so that bad argument does not leave broken image
+    let mut contents: Vec<(&str, Vec<u8>)> = Vec::new();
+    for file in files.iter() {
+        let short_name = split_path(file).1;
+        let short_name = short_name.strip_prefix('_').unwrap_or(short_name);
+        if let Err(e) = make_dirent(0, short_name) {
+            eprintln!("mkfs: {}", e);
+            exit(1);
+        }
+        if contents.iter().any(|(name, _)| *name == short_name) {
+            eprintln!("mkfs: {}: already exists.", short_name);
+            exit(1);
+        }
+        let data = match std::fs::read(file) {
+            Ok(d) => d,
+            Err(e) => {
+                eprintln!("mkfs: {}: {}", file, e);
+                exit(1);
+            }
+        };
+        if data.len() > BLOCK_SIZE * (NDIRECT + U32_PER_BLOCK) {
+            eprintln!(
+                "mkfs: {} is too large (must be {} bytes or smaller)",
+                file,
+                BLOCK_SIZE * (NDIRECT + U32_PER_BLOCK)
             );
+            exit(1);
+        }
+        contents.push((short_name, data));
+    }
 
-            for i in ref_block.iter() {
-                if *i == 0 {
-                    // allocate new block
-                    let block_num = search_for_available_dblock(&img, &sblock);
-                    if block_num.is_err() {
-                        eprintln!("put: {}", block_num.unwrap_err());
-                        exit(1);
-                    }
-                    let block_num = block_num.unwrap();
-                    let dblock: &mut [dirent; BLOCK_SIZE / DIRENT_SIZE] =
-                        extract_dirents_pointer(&img, block_num);
-
-                    // initialize block
-                    (*dblock)[0] = new_dirent;
-
-                    for i in 1..BLOCK_SIZE / DIRENT_SIZE {
-                        (*dblock)[i] = dirent {
-                            inum: 0,
-                            name: [0u8; DIRSIZ],
-                        };
-                    }
-                    (*ref_block)[*i as usize] = block_num as u32;
-                    return;
+    let file = match crate::file::create_image_file(path, size as usize * BLOCK_SIZE) {
+        Ok(f) => f,
+        Err(e) => {
+            eprintln!("mkfs: {}: {}", path, e);
+            exit(1);
+        }
+    };
+    let mut img = match crate::file::get_memory_mapped_file(&file, size as usize * BLOCK_SIZE) {
+        Ok(m) => m,
+        Err(e) => {
+            eprintln!("mkfs: {}", e);
+            exit(1);
+        }
+    };
+    format_image(&mut img, &sblock);
+    if !contents.is_empty() {
+        if let Err(e) = append_files_in_mkfs_order(&mut img, &contents, &sblock) {
+            eprintln!("mkfs: {}", e);
+            exit(1);
+        }
+    }
+    if let Err(e) = img.flush() {
+        eprintln!("mkfs: {}", e);
+        exit(1);
+    }
+}
+
+// write superblock, root directory and free bit map into zero-filled image
+fn format_image(img: &mut MmapMut, sblock: &superblock) {
+    let sblock_bytes = sblock::superblock_as_u8_slice(sblock);
+    img[BLOCK_SIZE..BLOCK_SIZE + sblock_bytes.len()].copy_from_slice(sblock_bytes);
+
+    // root directory takes the first data block
+    let root_block_num = sblock::datastart(sblock);
+    let dblock: &mut [dirent; BLOCK_SIZE / DIRENT_SIZE] =
+        extract_dirents_pointer(img, root_block_num);
+    dblock[0] = make_dirent(ROOT_INODE, ".").unwrap();
+    dblock[1] = make_dirent(ROOT_INODE, "..").unwrap();
+    let mut addrs = [0; NDIRECT + 1];
+    addrs[0] = root_block_num as u32;
+    *extract_inode_pointer(img, ROOT_INODE, sblock) = dinode {
+        r#type: InodeType::T_DIR,
+        major: 0,
+        minor: 0,
+        nlink: 1,
+        size: BLOCK_SIZE as u32,
+        addrs,
+    };
+
+    // metadata blocks and root directory block are in use
+    for block_num in 0..=root_block_num {
+        bitmap::switch(img, block_num, sblock);
+    }
+}
+
+// put files into root directory of newly formatted image, allocating inodes and blocks in
+// exactly the same order as xv6's mkfs does, so that the resulting image is byte-identical.
+fn append_files_in_mkfs_order(
+    img: &mut MmapMut,
+    files: &[(&str, Vec<u8>)],
+    sblock: &superblock,
+) -> Result<(), String> {
+    // blocks are handed out sequentially, just after root directory block
+    let mut freeblock = sblock::datastart(sblock) + 1;
+    let mut alloc = || -> Result<u32, String> {
+        if freeblock >= sblock.size as usize {
+            return Err("cannot allocate data block.".to_string());
+        }
+        freeblock += 1;
+        Ok((freeblock - 1) as u32)
+    };
+    let mut root_size = 2 * DIRENT_SIZE; // "." and ".."
+
+    for (name, data) in files.iter() {
+        let inode_num = search_for_available_inode(img, sblock)?;
+        let mut inode = dinode {
+            r#type: InodeType::T_FILE,
+            major: 0,
+            minor: 0,
+            nlink: 1,
+            size: data.len() as u32,
+            addrs: [0; NDIRECT + 1],
+        };
+
+        // append dirent to root directory
+        let fbn = root_size / BLOCK_SIZE;
+        if fbn >= NDIRECT {
+            return Err(format!("cannot add {}: directory is full", name));
+        }
+        let root: &mut dinode = extract_inode_pointer(img, ROOT_INODE, sblock);
+        if root.addrs[fbn] == 0 {
+            root.addrs[fbn] = alloc()?;
+        }
+        *extract_dirent_pointer(
+            img,
+            root.addrs[fbn] as usize,
+            (root_size % BLOCK_SIZE) / DIRENT_SIZE,
+        ) = make_dirent(inode_num, name)?;
+        root_size += DIRENT_SIZE;
+
+        // indirect reference block is allocated when it becomes necessary
+        for (fbn, chunk) in data.chunks(BLOCK_SIZE).enumerate() {
+            let block_num = if fbn < NDIRECT {
+                inode.addrs[fbn] = alloc()?;
+                inode.addrs[fbn]
+            } else {
+                if inode.addrs[NDIRECT] == 0 {
+                    inode.addrs[NDIRECT] = alloc()?;
+                }
+                let block_num = alloc()?;
+                extract_indirect_reference_block_pointer(img, inode.addrs[NDIRECT] as usize)
+                    [fbn - NDIRECT] = block_num;
+                block_num
+            };
+            extract_block_pointer(img, block_num as usize)[..chunk.len()].copy_from_slice(chunk);
+        }
+        *extract_inode_pointer(img, inode_num, sblock) = inode;
+    }
+
+    // like xv6's mkfs, size of root directory is rounded up to the next block boundary
+    extract_inode_pointer(img, ROOT_INODE, sblock).size =
+        ((root_size / BLOCK_SIZE + 1) * BLOCK_SIZE) as u32;
+
+    for block_num in sblock::datastart(sblock) + 1..freeblock {
+        bitmap::switch(img, block_num, sblock);
+    }
+    Ok(())
+}
+
+pub fn fsck(img: &mut MmapMut, repair: bool, sblock: &superblock) {
+    if repair {
+        match crate::fsck::repair(img, sblock) {
+            Ok(changes) => {
+                for change in changes.iter() {
+                    println!("{}", change);
                 }
+                println!("{} changes made.", changes.len());
             }
-            panic!("put: cannot add {} to {}", src, dst);
+            Err(e) => eprintln!("fsck: cannot repair: {}", e),
         }
-        // put into empty space
-        else {
-            let dirent: &mut dirent =
-                extract_dirent_pointer(&img, dirent_block_number, dirent_block_offset);
-            *dirent = new_dirent;
+    }
+    let problems = crate::fsck::check(img, sblock);
+    for problem in problems.iter() {
+        println!("{}", problem);
+    }
+    if !problems.is_empty() {
+        eprintln!("fsck: {} problems found.", problems.len());
+        exit(1);
+    }
+    println!("no problem found.");
+}
+
+pub fn diskinfo(img: &MmapMut, sblock: &superblock) {
+    let datastart = sblock::datastart(sblock);
+    let used_blocks = (datastart..sblock.size as usize)
+        .filter(|&b| bitmap::check(img, b, sblock))
+        .count();
+    let data_blocks = sblock.size as usize - datastart;
+    let bitmap_blocks = datastart.saturating_sub(sblock.bmapstart as usize);
+    let used_inodes = (ROOT_INODE..sblock.ninodes as usize)
+        .filter(|&i| raw_inode_type(img, i, sblock) != InodeType::ZERO as i16)
+        .count();
+    // inode 0 is never used
+    let inodes = sblock.ninodes as usize - 1;
+
+    println!("magic           : {:#x}", sblock.magic);
+    println!("size            : {} blocks", sblock.size);
+    println!("nblocks         : {} blocks", sblock.nblocks);
+    println!("ninodes         : {}", sblock.ninodes);
+    println!("nlog            : {} blocks", sblock.nlog);
+    println!("logstart        : {}", sblock.logstart);
+    println!("inodestart      : {}", sblock.inodestart);
+    println!("bmapstart       : {}", sblock.bmapstart);
+    println!("datastart       : {}", datastart);
+    println!("inodes per block: {}", BLOCK_SIZE / DINODE_SIZE);
+    println!("bitmap blocks   : {}", bitmap_blocks);
+    println!(
+        "data blocks     : {} used, {} free",
+        used_blocks,
+        data_blocks - used_blocks
+    );
+    println!(
+        "inodes          : {} used, {} free",
+        used_inodes,
+        inodes - used_inodes
+    );
+    println!(
+        "max file size   : {} bytes",
+        BLOCK_SIZE * (NDIRECT + U32_PER_BLOCK)
+    );
+    if bitmap_blocks * BLOCK_SIZE * 8 < sblock.size as usize {
+        // e.g. nblocks is too large, so data region overlaps other metadata
+        println!(
+            "warning: {} bitmap blocks before data region cannot cover {} blocks",
+            bitmap_blocks, sblock.size
+        );
+    }
+}
+
+// show every field of the inode given by path, or by inode number if path is None
+pub fn info(img: &MmapMut, path: Option<&str>, inode_num: usize, sblock: &superblock) {
+    let inode_num = match path {
+        Some(path) => match explore_path(img, path, sblock) {
+            Ok((_, n)) => n,
+            Err(e) => {
+                eprintln!("info: {}", e);
+                exit(1);
+            }
+        },
+        None => {
+            if inode_num >= sblock.ninodes as usize {
+                eprintln!(
+                    "info: inode {} is out of range (ninodes is {})",
+                    inode_num, sblock.ninodes
+                );
+                exit(1);
+            }
+            inode_num
         }
+    };
+    let inode = extract_inode_pointer_im(img, inode_num, sblock);
+    let r#type = raw_inode_type(img, inode_num, sblock);
+
+    println!("inode    : {}", inode_num);
+    match r#type {
+        0 => println!("type     : free"),
+        1..=3 => println!("type     : {}", inode.r#type),
+        _ => println!("type     : invalid ({})", r#type),
+    }
+    println!("major    : {}", inode.major);
+    println!("minor    : {}", inode.minor);
+    println!("nlink    : {}", inode.nlink);
+    if r#type == InodeType::T_DEV as i16 {
+        println!("device   : major {}, minor {}", inode.major, inode.minor);
     } else {
-        eprintln!("put: cannot override /.");
+        println!("size     : {} bytes", inode.size);
+    }
+
+    let datastart = sblock::datastart(sblock);
+    // note for block number out of data region
+    let note = |block_num: u32| {
+        let block_num = block_num as usize;
+        if block_num != 0 && (block_num < datastart || block_num >= sblock.size as usize) {
+            " (outside data region)"
+        } else {
+            ""
+        }
+    };
+    for (i, addr) in inode.addrs[..NDIRECT].iter().enumerate() {
+        println!("{:<9}: {}{}", format!("addrs[{}]", i), addr, note(*addr));
+    }
+    let indirect = inode.addrs[NDIRECT];
+    println!(
+        "{:<9}: {} (indirect){}",
+        format!("addrs[{}]", NDIRECT),
+        indirect,
+        note(indirect)
+    );
+    if indirect == 0 || !note(indirect).is_empty() {
+        return;
+    }
+    for (i, addr) in extract_indirect_reference_block_pointer_im(img, indirect as usize)
+        .iter()
+        .enumerate()
+    {
+        if *addr != 0 {
+            println!("  indirect[{}]: {}{}", i, addr, note(*addr));
+        }
+    }
+}
+
+// show log header, or install committed blocks if replay
+pub fn log(img: &mut MmapMut, replay: bool, sblock: &superblock) {
+    if replay {
+        match log::replay(img, sblock) {
+            Ok(block_nums) => println!("{} blocks are installed.", block_nums.len()),
+            Err(e) => {
+                eprintln!("log: {}", e);
+                exit(1);
+            }
+        }
+        return;
+    }
+    let block_nums = match log::read_header(img, sblock) {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("log: {}", e);
+            exit(1);
+        }
+    };
+    println!(
+        "log: {} blocks from block {} ({} blocks are committed)",
+        sblock.nlog,
+        sblock.logstart,
+        block_nums.len()
+    );
+    for (i, block_num) in block_nums.iter().enumerate() {
+        println!(
+            "  block {} -> block {}",
+            sblock.logstart as usize + 1 + i,
+            block_num
+        );
+    }
+}
+
+// check consistency of images crashed at every point of block writes updating img into new.
+// recorded is the writes (block number, contents) recorded while running the command,
+// or None to write the changes through log (same as transactional mode).
+pub fn crashtest(
+    img: &MmapMut,
+    new: &[u8],
+    recorded: Option<Vec<(usize, Vec<u8>)>>,
+    recover: bool,
+    sblock: &superblock,
+) {
+    let writes: Vec<(usize, Vec<u8>)> = match recorded {
+        Some(w) => w,
+        None => match log::transaction_writes(img, new, sblock) {
+            Ok(w) => w,
+            Err(e) => {
+                eprintln!("crashtest: {}", e);
+                exit(1);
+            }
+        },
+    };
+
+    let mut crashed: Vec<u8> = img.to_vec();
+    let mut inconsistent: usize = 0;
+    for k in 0..=writes.len() {
+        if k == 0 {
+            print!("crash before any write: ");
+        } else {
+            let (block_num, data) = &writes[k - 1];
+            crashed[block_num * BLOCK_SIZE..(block_num + 1) * BLOCK_SIZE].copy_from_slice(data);
+            print!(
+                "crash after write {}/{} (block {}): ",
+                k,
+                writes.len(),
+                block_num
+            );
+        }
+        let image = crashed.clone();
+        if recover {
+            if let Err(e) = log::replay(&image, sblock) {
+                println!("recovery failed: {}", e);
+                inconsistent += 1;
+                continue;
+            }
+        }
+        let problems = crate::fsck::check(&image, sblock);
+        if problems.is_empty() {
+            println!("ok");
+            continue;
+        }
+        inconsistent += 1;
+        println!("{} problems", problems.len());
+        for problem in problems.iter() {
+            println!("    {}", problem);
+        }
+    }
+
+    if inconsistent != 0 {
+        eprintln!(
+            "crashtest: {} of {} crash points are inconsistent.",
+            inconsistent,
+            writes.len() + 1
+        );
+        exit(1);
+    }
+    println!(
+        "crashtest: all {} crash points are consistent.",
+        writes.len() + 1
+    );
+}
+
+// break the image on purpose, and show what is done and what fsck is expected to find
+pub fn corrupt(img: &mut MmapMut, kind: &str, seed: Option<u64>, sblock: &superblock) {
+    let seed = seed.unwrap_or_else(|| {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0)
+    });
+    let already = crate::fsck::check(img, sblock).len();
+    if already != 0 {
+        eprintln!(
+            "corrupt: warning: image already has {} problems, so fsck may find something other than expected",
+            already
+        );
+    }
+    let record = match crate::corrupt::corrupt(img, kind, seed, sblock) {
+        Ok(r) => r,
+        Err(e) => {
+            eprintln!("corrupt: {}", e);
+            exit(1);
+        }
+    };
+    println!("seed: {}", seed);
+    println!("corrupted: {}", record.description);
+    println!("expected fsck findings:");
+    for problem in record.expected.iter() {
+        println!("    {}", problem);
+    }
+}
+
+// raw edits below write fields as they are, bypassing any consistency logic on purpose.
+
+// number in decimal, or in hexadecimal with "0x"
+fn parse_number(value: &str) -> Result<i64, String> {
+    let parsed = match value.strip_prefix("0x") {
+        Some(hex) => i64::from_str_radix(hex, 16),
+        None => value.parse::<i64>(),
+    };
+    parsed.map_err(|e| format!("{}: {}", value, e))
+}
+
+// write value into img[addr..addr + len] as little endian, checking that it fits in len bytes
+fn write_field(img: &mut [u8], addr: usize, len: usize, value: &str) -> Result<(), String> {
+    let n = parse_number(value)?;
+    let bytes = match len {
+        2 if n >= i16::MIN as i64 && n <= u16::MAX as i64 => (n as u16).to_le_bytes().to_vec(),
+        4 if n >= i32::MIN as i64 && n <= u32::MAX as i64 => (n as u32).to_le_bytes().to_vec(),
+        _ => return Err(format!("{}: out of range for {} bytes field", value, len)),
+    };
+    record::access(img, addr / BLOCK_SIZE);
+    img[addr..addr + len].copy_from_slice(&bytes);
+    Ok(())
+}
+
+pub const SUPERBLOCK_FIELDS: [&str; 8] = [
+    "magic",
+    "size",
+    "nblocks",
+    "ninodes",
+    "nlog",
+    "logstart",
+    "inodestart",
+    "bmapstart",
+];
+
+pub fn set_super(img: &mut MmapMut, field: &str, value: &str) {
+    // fields of superblock are u32 in this order
+    let index = match SUPERBLOCK_FIELDS.iter().position(|&f| f == field) {
+        Some(i) => i,
+        None => {
+            eprintln!("set-super: unknown field: {}", field);
+            exit(1);
+        }
+    };
+    if let Err(e) = write_field(img, BLOCK_SIZE + index * 4, 4, value) {
+        eprintln!("set-super: {}", e);
+        exit(1);
+    }
+}
+
+pub fn set_inode(
+    img: &mut MmapMut,
+    inode_num: usize,
+    field: &str,
+    value: &str,
+    sblock: &superblock,
+) {
+    if inode_num >= sblock.ninodes as usize {
+        eprintln!(
+            "set-inode: inode {} is out of range (ninodes is {})",
+            inode_num, sblock.ninodes
+        );
+        exit(1);
+    }
+    // (offset, length) of the field in dinode
+    let (offset, len) = match field {
+        "type" => (0, 2),
+        "major" => (2, 2),
+        "minor" => (4, 2),
+        "nlink" => (6, 2),
+        "size" => (8, 4),
+        _ => match field
+            .strip_prefix("addrs[")
+            .and_then(|f| f.strip_suffix(']'))
+            .and_then(|i| i.parse::<usize>().ok())
+        {
+            Some(i) if i <= NDIRECT => (12 + 4 * i, 4),
+            _ => {
+                eprintln!(
+                    "set-inode: unknown field: {} (must be type, major, minor, nlink, size or addrs[0..={}])",
+                    field, NDIRECT
+                );
+                exit(1);
+            }
+        },
+    };
+    // other commands read type as InodeType, which cannot hold other values
+    if field == "type" && !matches!(parse_number(value), Ok(0..=3)) {
+        eprintln!(
+            "set-inode: {}: type must be 0 (free), 1 (directory), 2 (file) or 3 (device file)",
+            value
+        );
+        exit(1);
+    }
+    let addr = sblock.inodestart as usize * BLOCK_SIZE + inode_num * DINODE_SIZE + offset;
+    if let Err(e) = write_field(img, addr, len, value) {
+        eprintln!("set-inode: {}", e);
+        exit(1);
+    }
+}
+
+pub fn set_dirent(
+    img: &mut MmapMut,
+    dir_inode_num: usize,
+    slot: usize,
+    inode_num: u16,
+    name: &str,
+    sblock: &superblock,
+) {
+    if dir_inode_num >= sblock.ninodes as usize {
+        eprintln!(
+            "set-dirent: inode {} is out of range (ninodes is {})",
+            dir_inode_num, sblock.ninodes
+        );
+        exit(1);
+    }
+    if name.len() > DIRSIZ {
+        eprintln!(
+            "set-dirent: {}: file name too long (must be {} bytes or shorter)",
+            name, DIRSIZ
+        );
+        exit(1);
+    }
+    let dir_inode = extract_inode_pointer_im(img, dir_inode_num, sblock);
+    let fbn = slot * DIRENT_SIZE / BLOCK_SIZE;
+    let block_num = inode_block_number(img, dir_inode, fbn);
+    if block_num == 0 || block_num >= sblock.size as usize {
+        eprintln!(
+            "set-dirent: slot {} is in block {} of inode {}, which is not allocated",
+            slot, fbn, dir_inode_num
+        );
+        exit(1);
+    }
+    let mut new_name = [0u8; DIRSIZ];
+    new_name[..name.len()].copy_from_slice(name.as_bytes());
+    *extract_dirent_pointer(img, block_num, slot % (BLOCK_SIZE / DIRENT_SIZE)) = dirent {
+        inum: inode_num,
+        name: new_name,
+    };
+}
+
+pub fn bitmap(img: &mut MmapMut, block_num: usize, used: bool, sblock: &superblock) {
+    if block_num >= sblock.size as usize {
+        eprintln!(
+            "bitmap: block {} is out of image (size is {})",
+            block_num, sblock.size
+        );
         exit(1);
     }
+    bitmap::mark(img, block_num, used, sblock);
 }
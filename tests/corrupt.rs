@@ -0,0 +1,68 @@
+use opfs::block::sblock;
+use opfs::{corrupt, fsck};
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+fn opfs(dir: &Path, args: &[&str]) {
+    let status = Command::new(env!("CARGO_BIN_EXE_opfs"))
+        .current_dir(dir)
+        .args(args)
+        .status()
+        .unwrap();
+    assert!(status.success(), "opfs {:?} failed", args);
+}
+
+// consistent image with nested directories, and files using direct and indirect blocks
+fn make_image() -> Vec<u8> {
+    let dir = std::env::temp_dir().join(format!("opfs-corrupt-test-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("README"), b"corrupt test\n".repeat(70)).unwrap();
+    fs::write(
+        dir.join("big"),
+        (0..20000).map(|i| (i % 251) as u8).collect::<Vec<u8>>(),
+    )
+    .unwrap();
+    fs::write(dir.join("hi"), b"hello\n").unwrap();
+    opfs(&dir, &["mkfs", "fs.img", "README", "big", "hi"]);
+    opfs(&dir, &["fs.img", "mkdir", "-p", "/a/b/c"]);
+    opfs(&dir, &["fs.img", "mkdir", "/d"]);
+    opfs(&dir, &["fs.img", "put", "README", "/a/b/r"]);
+    opfs(&dir, &["fs.img", "put", "hi", "/d/h"]);
+    opfs(&dir, &["fs.img", "put", "big", "/a/big"]);
+    let img = fs::read(dir.join("fs.img")).unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+    img
+}
+
+// what each corruption expects is exactly what fsck finds
+#[test]
+fn expected_findings_match_fsck() {
+    let base = make_image();
+    let sblock = sblock::u8_slice_as_superblock(&base);
+    assert!(fsck::check(&base, &sblock).is_empty());
+
+    for kind in corrupt::KINDS.iter() {
+        let mut images: Vec<Vec<u8>> = Vec::new();
+        for seed in 0..10 {
+            let img = base.clone();
+            let record = corrupt::corrupt(&img, kind, seed, &sblock).unwrap();
+            assert!(!record.expected.is_empty(), "{} (seed {})", kind, seed);
+            assert_eq!(
+                fsck::check(&img, &sblock),
+                record.expected,
+                "{} (seed {}): {}",
+                kind,
+                seed,
+                record.description
+            );
+            images.push(img);
+        }
+        // every bit of seed is used
+        assert!(
+            (0..5).any(|k| images[2 * k] != images[2 * k + 1]),
+            "{}: seeds 2k and 2k+1 give the same corruption",
+            kind
+        );
+    }
+}
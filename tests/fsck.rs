@@ -0,0 +1,43 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+fn opfs(dir: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_opfs"))
+        .current_dir(dir)
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("opfs-{}-{}", name, std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn repair_refuses_root_which_is_not_directory() {
+    let dir = temp_dir("fsck-root");
+    fs::write(dir.join("hello"), b"hello, world\n").unwrap();
+    assert!(opfs(&dir, &["mkfs", "fs.img", "hello"]).status.success());
+    assert!(opfs(&dir, &["fs.img", "mkdir", "/d"]).status.success());
+    assert!(opfs(&dir, &["fs.img", "set-inode", "1", "type", "2"])
+        .status
+        .success());
+    let before = fs::read(dir.join("fs.img")).unwrap();
+
+    let output = opfs(&dir, &["fs.img", "fsck", "--repair"]);
+    let after = fs::read(dir.join("fs.img")).unwrap();
+    assert!(opfs(&dir, &["fs.img", "set-inode", "1", "type", "1"])
+        .status
+        .success());
+    let fsck = opfs(&dir, &["fs.img", "fsck"]);
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("cannot repair"));
+    // neither root nor anything under it is released
+    assert!(before == after, "repair changed the image");
+    assert!(fsck.status.success());
+}
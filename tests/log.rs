@@ -0,0 +1,30 @@
+use opfs::block::log;
+use opfs::block::sblock;
+use opfs::BLOCK_SIZE;
+
+// a log longer than its header can describe: each transaction must still fit in the header
+#[test]
+fn transaction_fits_in_log_header() {
+    let sblock = sblock::new_superblock(5000, 200, 300).unwrap();
+    let old = vec![0u8; sblock.size as usize * BLOCK_SIZE];
+    let mut new = old.clone();
+    // 270 blocks of file data
+    let datastart = sblock::datastart(&sblock);
+    for b in datastart..datastart + 270 {
+        new[b * BLOCK_SIZE..(b + 1) * BLOCK_SIZE].fill((b % 251) as u8 + 1);
+    }
+
+    let writes = log::transaction_writes(&old, &new, &sblock).unwrap();
+    let mut img = old.clone();
+    let logstart = sblock.logstart as usize;
+    for (block_num, data) in writes.iter() {
+        img[block_num * BLOCK_SIZE..(block_num + 1) * BLOCK_SIZE].copy_from_slice(data);
+        if *block_num == logstart {
+            // count and block numbers must be readable back
+            log::read_header(&img, &sblock).unwrap();
+        }
+    }
+    // outside log region, writes update old image into new one
+    let logend = (logstart + sblock.nlog as usize) * BLOCK_SIZE;
+    assert!(img[logend..] == new[logend..]);
+}
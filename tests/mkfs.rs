@@ -0,0 +1,67 @@
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+
+const BLOCK_SIZE: usize = 1024;
+
+// tests/data/mkfs.img was made by xv6's mkfs (built with FSSIZE=200) from the files below:
+//   mkfs mkfs.img README empty zeros _big f00 f01 ... f63
+fn input_files() -> Vec<(String, Vec<u8>)> {
+    let mut files = vec![
+        (
+            "README".to_string(),
+            b"opfs mkfs compatibility test\n".repeat(10),
+        ),
+        // no data block
+        ("empty".to_string(), vec![]),
+        // zero-filled blocks are allocated as well
+        ("zeros".to_string(), vec![0; 3 * BLOCK_SIZE + 100]),
+        // 15 blocks: uses the indirect block, and '_' is stripped from the name
+        (
+            "_big".to_string(),
+            (0..15000).map(|i| (i % 251) as u8).collect(),
+        ),
+    ];
+    // 68 files in total: root directory needs the second block
+    for i in 0..64 {
+        files.push((
+            format!("f{:02}", i),
+            format!("file {}\n", i).repeat(i).into_bytes(),
+        ));
+    }
+    files
+}
+
+#[test]
+fn mkfs_is_compatible_with_xv6() {
+    let dir = std::env::temp_dir().join(format!("opfs-mkfs-test-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let files = input_files();
+    for (name, data) in files.iter() {
+        fs::write(dir.join(name), data).unwrap();
+    }
+    let img_path: PathBuf = dir.join("fs.img");
+
+    let status = Command::new(env!("CARGO_BIN_EXE_opfs"))
+        .current_dir(&dir)
+        .arg("mkfs")
+        .arg(&img_path)
+        .args(&["--size", "200"])
+        .args(files.iter().map(|(name, _)| name))
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let img = fs::read(&img_path).unwrap();
+    let expected = include_bytes!("data/mkfs.img");
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(img.len(), expected.len());
+    for (i, (b, e)) in img
+        .chunks(BLOCK_SIZE)
+        .zip(expected.chunks(BLOCK_SIZE))
+        .enumerate()
+    {
+        assert!(b == e, "block {} differs from xv6's mkfs", i);
+    }
+}